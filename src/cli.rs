@@ -0,0 +1,40 @@
+use clap::{Parser, Subcommand};
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "hello_plot_max",
+    version,
+    about = "Distribute new plots to the final disks and replace old plots."
+)]
+pub struct Cli {
+    /// Path of the user settings file
+    #[arg(long, global = true, default_value = "./userset.json")]
+    pub config: String,
+
+    /// Path of the log4rs settings file
+    #[arg(long, global = true, default_value = "./log4rs.yaml")]
+    pub log_config: String,
+
+    /// Directory of requests.log
+    #[arg(long, global = true, default_value = "./log")]
+    pub log_dir: String,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Move plots to the final disks (default)
+    Run,
+    /// Show the final disks and the unfinished transfers
+    Status,
+    /// Simulate the distribution without moving, creating or deleting any file
+    Plan,
+    /// Check the user settings file and exit
+    ValidateConfig,
+    /// List the plots on every final disk
+    Scan,
+    /// Remove leftover temp files from the final disks
+    CleanTmp,
+}
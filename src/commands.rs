@@ -0,0 +1,219 @@
+use std::path::Path;
+
+use log::info;
+use prettytable::{Cell, Row, Table};
+
+use super::journal::*;
+use super::plot_format::*;
+use super::show::*;
+use super::space::*;
+use super::userset::*;
+
+pub async fn status(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let user_set = get_user_set(config_path).await?;
+
+    // 源目录中等待传输的图
+    for source in user_set.source_dirs() {
+        let source_plots = scan_sources(std::slice::from_ref(&source)).await?;
+        println!(
+            "Source path {} (priority {}):{} plots waiting",
+            source.path,
+            source.priority,
+            source_plots.len()
+        );
+    }
+
+    // 每个最终目录的概况
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![
+        Cell::new("ID"),
+        Cell::new("Final Path"),
+        Cell::new("Plots"),
+        Cell::new("Plots Size"),
+        Cell::new("Other Files"),
+        Cell::new("Capacity"),
+        Cell::new("Free"),
+    ]));
+    for (id, item) in user_set.final_dirs.iter().enumerate() {
+        let plots = scan_plot(&item.path).await?;
+        let mut plots_size = 0_f32;
+        for plot in plots.iter() {
+            plots_size += get_plot_size(&format!("{}/{}", item.path, plot)).await?;
+        }
+        let other_files = std::fs::read_dir(&item.path)?.count() - plots.len();
+        let space = disk_space(&item.path)?;
+        table.add_row(Row::new(vec![
+            Cell::new(&id.to_string()),
+            Cell::new(&item.path),
+            Cell::new(&plots.len().to_string()),
+            Cell::new(&format!("{}GB", plots_size)),
+            Cell::new(&other_files.to_string()),
+            Cell::new(&format!("{}GB", space.total)),
+            Cell::new(&format!("{}GB", space.free)),
+        ]));
+    }
+    table.print_tty(true)?;
+
+    // 未完成的传输
+    let pending = read_pending(&journal_path(config_path))?;
+    if pending.is_empty() {
+        println!("No unfinished transfer.");
+    }
+    for entry in pending {
+        println!(
+            "Unfinished transfer:{} -> {} ({:?}, {} bytes copied)",
+            entry.source, entry.target, entry.phase, entry.offset
+        );
+    }
+    Ok(())
+}
+
+pub async fn validate_config(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let user_set = get_user_set(config_path).await?;
+    let mut problems = vec![];
+
+    let sources = user_set.source_dirs();
+    if sources.is_empty() {
+        problems.push("neither source_dir_path nor sources is set".to_owned());
+    }
+    for (id, source) in sources.iter().enumerate() {
+        if !Path::new(&source.path).is_dir() {
+            problems.push(format!(
+                "sources[{}] {} is not a directory",
+                id, source.path
+            ));
+        }
+        if let Some(filter) = &source.filter {
+            if let Err(e) = glob::Pattern::new(filter) {
+                problems.push(format!(
+                    "sources[{}] filter {} is invalid:{}",
+                    id, filter, e
+                ));
+            }
+        }
+    }
+    if user_set.hdd_limit_rate <= 0.0 {
+        problems.push(format!(
+            "hdd_limit_rate {} must be greater than 0",
+            user_set.hdd_limit_rate
+        ));
+    }
+    for (name, rate) in [
+        ("source_limit_rate", user_set.source_limit_rate),
+        ("global_limit_rate", user_set.global_limit_rate),
+    ] {
+        if let Some(rate) = rate {
+            if rate <= 0.0 {
+                problems.push(format!("{} {} must be greater than 0", name, rate));
+            }
+        }
+    }
+    if user_set.final_dirs.is_empty() {
+        problems.push("final_dirs is empty".to_owned());
+    }
+    for (id, item) in user_set.final_dirs.iter().enumerate() {
+        if !Path::new(&item.path).is_dir() {
+            problems.push(format!(
+                "final_dirs[{}] {} is not a directory",
+                id, item.path
+            ));
+        }
+        if let Some(size) = item.size {
+            if size <= 0.0 {
+                problems.push(format!(
+                    "final_dirs[{}] size {} must be greater than 0",
+                    id, size
+                ));
+            }
+        }
+        if user_set.final_dirs[..id]
+            .iter()
+            .any(|other| other.path == item.path)
+        {
+            problems.push(format!("final_dirs[{}] {} is duplicated", id, item.path));
+        }
+    }
+
+    let policy = &user_set.replacement_policy;
+    let contracts = policy
+        .rules
+        .iter()
+        .filter_map(|rule| rule.pool_contract.as_ref())
+        .chain(policy.protected_contracts.iter());
+    for contract in contracts {
+        let hex = contract.trim_start_matches("0x");
+        if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            problems.push(format!(
+                "pool contract {} is not a 32 bytes hex string",
+                contract
+            ));
+        }
+    }
+
+    if problems.is_empty() {
+        println!("{}:OK", config_path);
+        return Ok(());
+    }
+    for problem in problems.iter() {
+        println!("{}:{}", config_path, problem);
+    }
+    Err(format!("{} problems found in {}", problems.len(), config_path).into())
+}
+
+pub async fn scan(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let user_set = get_user_set(config_path).await?;
+
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![
+        Cell::new("Final Path"),
+        Cell::new("Plot"),
+        Cell::new("K"),
+        Cell::new("Pool"),
+        Cell::new("Compression Level"),
+        Cell::new("Size"),
+    ]));
+    for item in user_set.final_dirs.iter() {
+        for plot in scan_plot(&item.path).await? {
+            let plot_path = format!("{}/{}", item.path, plot);
+            let plot_size = get_plot_size(&plot_path).await?;
+            let (k, pool, compression_level) = match read_plot_header(&plot_path).await {
+                Ok(header) => (
+                    header.k.to_string(),
+                    match header.pool_contract_hex() {
+                        Some(contract) => format!("NFT {}", contract),
+                        None => "OG".to_owned(),
+                    },
+                    header.compression_level.to_string(),
+                ),
+                Err(_) => ("?".to_owned(), "unrecognized".to_owned(), "?".to_owned()),
+            };
+            table.add_row(Row::new(vec![
+                Cell::new(&item.path),
+                Cell::new(&plot),
+                Cell::new(&k),
+                Cell::new(&pool),
+                Cell::new(&compression_level),
+                Cell::new(&format!("{}GB", plot_size)),
+            ]));
+        }
+    }
+    table.print_tty(true)?;
+    Ok(())
+}
+
+pub async fn clean_tmp(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let user_set = get_user_set(config_path).await?;
+
+    // 可续传的临时文件不删除
+    let keep_temps: Vec<String> = read_pending(&journal_path(config_path))?
+        .into_iter()
+        .filter(|entry| entry.phase == TransferPhase::Copying)
+        .map(|entry| entry.temp)
+        .collect();
+    for item in user_set.final_dirs.iter() {
+        remove_tmp(&item.path, &keep_temps).await?;
+        info!("{}:Non plot file deletion completed.", item.path);
+        println!("{}:Non plot file deletion completed.", item.path);
+    }
+    Ok(())
+}
@@ -0,0 +1,467 @@
+use std::alloc::{self, Layout};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use super::limiter::*;
+
+// O_DIRECT 要求缓冲区地址、长度和文件偏移都按块对齐
+const ALIGN: usize = 4096;
+const MIN_THROTTLE_SLEEP: Duration = Duration::from_millis(10);
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CopySet {
+    // 每个缓冲区的大小，读写各用一个交替进行
+    #[serde(default = "default_buffer_mb")]
+    pub buffer_mb: usize,
+    // 写目标文件时绕过页缓存
+    #[serde(default)]
+    pub direct_io: bool,
+    // 复制后通知内核丢弃源文件和目标文件的页缓存
+    #[serde(default = "default_true")]
+    pub drop_cache: bool,
+    // 复制前为目标文件预分配空间
+    #[serde(default = "default_true")]
+    pub preallocate: bool,
+    // 每复制这么多数据落盘一次并回调进度
+    #[serde(default = "default_checkpoint_mb")]
+    pub checkpoint_mb: usize,
+    // 源目录和最终目录在同一文件系统时直接重命名，或用reflink/copy_file_range在内核中复制
+    #[serde(default = "default_true")]
+    pub fast_path: bool,
+}
+
+impl Default for CopySet {
+    fn default() -> Self {
+        CopySet {
+            buffer_mb: default_buffer_mb(),
+            direct_io: false,
+            drop_cache: true,
+            preallocate: true,
+            checkpoint_mb: default_checkpoint_mb(),
+            fast_path: true,
+        }
+    }
+}
+
+fn default_buffer_mb() -> usize {
+    8
+}
+
+fn default_checkpoint_mb() -> usize {
+    1024
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyMethod {
+    Rename,
+    Reflink,
+    CopyFileRange,
+    Stream,
+}
+
+impl fmt::Display for CopyMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CopyMethod::Rename => "rename",
+            CopyMethod::Reflink => "reflink",
+            CopyMethod::CopyFileRange => "copy_file_range",
+            CopyMethod::Stream => "stream",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CopyProgress {
+    // 已经落盘的字节数，可以作为续传的起点
+    pub offset: u64,
+    // 本次复制的平均速度 MB/s
+    pub rate: f32,
+}
+
+pub struct CopyJob<'a> {
+    pub source: &'a str,
+    pub target: &'a str,
+    pub offset: u64,
+    pub final_dir: &'a str,
+}
+
+pub struct CopyOutcome {
+    pub bytes: u64,
+    pub hash: blake3::Hash,
+}
+
+struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+// 缓冲区只在读写两个线程之间整体移交，不会被同时访问
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    fn new(size: usize) -> Self {
+        let size = size.max(ALIGN).div_ceil(ALIGN) * ALIGN;
+        let layout = Layout::from_size_align(size, ALIGN).unwrap();
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedBuffer { ptr, layout }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+// 阻塞执行，需要在 spawn_blocking 中调用。
+// 读线程和写线程各持有一个缓冲区交替工作，读的同时计算哈希
+pub fn copy_file(
+    set: &CopySet,
+    job: &CopyJob,
+    limiter: &RateLimiter,
+    mut on_progress: impl FnMut(CopyProgress) -> io::Result<()>,
+) -> io::Result<CopyOutcome> {
+    let mut source_file = File::open(job.source)?;
+    let source_metadata = source_file.metadata()?;
+    let source_len = source_metadata.len();
+    let source_dev = source_metadata.dev();
+
+    let mut options = OpenOptions::new();
+    options.create(true).truncate(false).write(true);
+    let mut direct = false;
+    let mut target_file = if set.direct_io {
+        // 部分文件系统(如tmpfs)不支持O_DIRECT，退回到普通写入
+        match options
+            .clone()
+            .custom_flags(libc::O_DIRECT)
+            .open(job.target)
+        {
+            Ok(file) => {
+                direct = true;
+                file
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                debug!("[Copy]:O_DIRECT is not supported on {}", job.target);
+                options.open(job.target)?
+            }
+            Err(e) => return Err(e),
+        }
+    } else {
+        options.open(job.target)?
+    };
+
+    // 丢弃offset之后未确认落盘的数据；使用O_DIRECT时续传位置需要对齐
+    let offset = if direct {
+        job.offset / ALIGN as u64 * ALIGN as u64
+    } else {
+        job.offset
+    };
+    target_file.set_len(offset)?;
+    if set.preallocate && source_len > offset {
+        preallocate(&target_file, offset, source_len - offset);
+    }
+
+    // 续传时先补算已复制部分的哈希
+    let mut hasher = blake3::Hasher::new();
+    if offset > 0 {
+        let mut prefix = (&mut source_file).take(offset);
+        io::copy(&mut prefix, &mut hasher)?;
+    }
+    source_file.seek(SeekFrom::Start(offset))?;
+    target_file.seek(SeekFrom::Start(offset))?;
+
+    let buffer_size = set.buffer_mb.max(1) * 1024 * 1024;
+    let checkpoint_bytes = (set.checkpoint_mb.max(1) * 1024 * 1024) as u64;
+    let (full_tx, full_rx) = mpsc::sync_channel::<(AlignedBuffer, usize)>(1);
+    let (empty_tx, empty_rx) = mpsc::sync_channel::<AlignedBuffer>(2);
+    empty_tx.send(AlignedBuffer::new(buffer_size)).unwrap();
+    empty_tx.send(AlignedBuffer::new(buffer_size)).unwrap();
+
+    std::thread::scope(|scope| {
+        let drop_cache = set.drop_cache;
+        let reader = scope.spawn(move || -> io::Result<blake3::Hash> {
+            let mut position = offset;
+            while let Ok(mut buffer) = empty_rx.recv() {
+                let len = read_full(&mut source_file, buffer.as_mut_slice())?;
+                if len == 0 {
+                    break;
+                }
+                hasher.update(&buffer.as_slice()[..len]);
+                if drop_cache {
+                    fadvise_dontneed(&source_file, position, len as u64);
+                }
+                position += len as u64;
+                if full_tx.send((buffer, len)).is_err() {
+                    break;
+                }
+            }
+            Ok(hasher.finalize())
+        });
+
+        let start_time = Instant::now();
+        let mut total_bytes = offset;
+        let mut last_checkpoint = offset;
+        let write_result = (|| -> io::Result<()> {
+            for (buffer, len) in full_rx.iter() {
+                // 最后一块长度不对齐，关闭O_DIRECT再写
+                if direct && len % ALIGN != 0 {
+                    clear_direct(&target_file)?;
+                    direct = false;
+                }
+                target_file.write_all(&buffer.as_slice()[..len])?;
+                total_bytes += len as u64;
+                let _ = empty_tx.send(buffer);
+
+                // 令牌桶限速，欠账累积到一定程度再一次性等待，避免过短的sleep
+                let wait = limiter.acquire(job.final_dir, source_dev, len as u64);
+                if wait >= MIN_THROTTLE_SLEEP {
+                    std::thread::sleep(wait);
+                }
+
+                if total_bytes - last_checkpoint >= checkpoint_bytes {
+                    target_file.sync_data()?;
+                    if set.drop_cache {
+                        fadvise_dontneed(
+                            &target_file,
+                            last_checkpoint,
+                            total_bytes - last_checkpoint,
+                        );
+                    }
+                    last_checkpoint = total_bytes;
+                    on_progress(CopyProgress {
+                        offset: total_bytes,
+                        rate: rate_mb(total_bytes - offset, start_time),
+                    })?;
+                }
+            }
+            Ok(())
+        })();
+        // 写失败时让读线程尽快退出
+        drop(full_rx);
+        drop(empty_tx);
+        let hash = reader.join().unwrap()?;
+        write_result?;
+
+        target_file.sync_all()?;
+        if set.drop_cache {
+            fadvise_dontneed(&target_file, 0, 0);
+        }
+        on_progress(CopyProgress {
+            offset: total_bytes,
+            rate: rate_mb(total_bytes - offset, start_time),
+        })?;
+        Ok(CopyOutcome {
+            bytes: total_bytes,
+            hash,
+        })
+    })
+}
+
+// 阻塞执行。依次尝试同一文件系统上的快速方式，都不可用时返回None，由调用者退回到普通复制。
+// Rename 直接把源文件移动到 final_target，其它方式把数据复制到 job.target
+pub fn fast_copy(
+    job: &CopyJob,
+    final_target: &str,
+    limiter: &RateLimiter,
+) -> io::Result<Option<CopyMethod>> {
+    let source_dev = std::fs::metadata(job.source)?.dev();
+    let same_device = source_dev == std::fs::metadata(job.final_dir)?.dev();
+
+    if same_device && try_rename(job.source, final_target)? {
+        return Ok(Some(CopyMethod::Rename));
+    }
+    // btrfs的子卷设备号不同但可以reflink，失败的代价很小，所以总是尝试
+    if try_reflink(job.source, job.target)? {
+        return Ok(Some(CopyMethod::Reflink));
+    }
+    if same_device && try_copy_file_range(job, source_dev, limiter)? {
+        return Ok(Some(CopyMethod::CopyFileRange));
+    }
+    Ok(None)
+}
+
+// 这些错误表示文件系统不支持该方式，而不是复制失败
+fn unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EXDEV)
+            | Some(libc::EOPNOTSUPP)
+            | Some(libc::ENOTTY)
+            | Some(libc::EINVAL)
+            | Some(libc::ENOSYS)
+    )
+}
+
+fn try_rename(source: &str, final_target: &str) -> io::Result<bool> {
+    match std::fs::rename(source, final_target) {
+        Ok(()) => Ok(true),
+        Err(e) if unsupported(&e) => {
+            debug!("[Copy]:Can not rename {} to {}:{}", source, final_target, e);
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn try_reflink(source: &str, target: &str) -> io::Result<bool> {
+    let source_file = File::open(source)?;
+    let target_file = File::create(target)?;
+    let ret = unsafe {
+        libc::ioctl(
+            target_file.as_raw_fd(),
+            libc::FICLONE,
+            source_file.as_raw_fd(),
+        )
+    };
+    if ret == 0 {
+        target_file.sync_all()?;
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    if unsupported(&e) {
+        debug!("[Copy]:Can not reflink {} to {}:{}", source, target, e);
+        Ok(false)
+    } else {
+        Err(e)
+    }
+}
+
+// 在内核中复制，数据不经过用户空间；仍然按块限速
+fn try_copy_file_range(job: &CopyJob, source_dev: u64, limiter: &RateLimiter) -> io::Result<bool> {
+    const CHUNK: usize = 64 * 1024 * 1024;
+    let source_file = File::open(job.source)?;
+    let target_file = File::create(job.target)?;
+    let mut copied = 0_u64;
+    loop {
+        let ret = unsafe {
+            libc::copy_file_range(
+                source_file.as_raw_fd(),
+                std::ptr::null_mut(),
+                target_file.as_raw_fd(),
+                std::ptr::null_mut(),
+                CHUNK,
+                0,
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if copied == 0 && unsupported(&e) {
+                debug!("[Copy]:Can not copy_file_range {}:{}", job.source, e);
+                return Ok(false);
+            }
+            return Err(e);
+        }
+        if ret == 0 {
+            break;
+        }
+        copied += ret as u64;
+        let wait = limiter.acquire(job.final_dir, source_dev, ret as u64);
+        if wait >= MIN_THROTTLE_SLEEP {
+            std::thread::sleep(wait);
+        }
+    }
+    target_file.sync_all()?;
+    Ok(true)
+}
+
+// 重新读取文件计算哈希；丢弃页缓存后读到的是盘上真实的数据
+pub fn hash_file(path: &str, set: &CopySet) -> io::Result<blake3::Hash> {
+    let mut file = File::open(path)?;
+    if set.drop_cache {
+        fadvise_dontneed(&file, 0, 0);
+    }
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = AlignedBuffer::new(set.buffer_mb.max(1) * 1024 * 1024);
+    loop {
+        let len = file.read(buffer.as_mut_slice())?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buffer.as_slice()[..len]);
+    }
+    if set.drop_cache {
+        fadvise_dontneed(&file, 0, 0);
+    }
+    Ok(hasher.finalize())
+}
+
+fn rate_mb(bytes: u64, start_time: Instant) -> f32 {
+    bytes as f32 / start_time.elapsed().as_secs_f32() / 1024.0 / 1024.0
+}
+
+// 读满整个缓冲区，只有文件末尾才会返回较短的长度
+fn read_full(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match file.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+fn preallocate(file: &File, offset: u64, len: u64) {
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret != 0 {
+        debug!(
+            "[Copy]:fallocate is not supported:{}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+fn fadvise_dontneed(file: &File, offset: u64, len: u64) {
+    unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_DONTNEED,
+        );
+    }
+}
+
+fn clear_direct(file: &File) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
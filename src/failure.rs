@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+// 第一次失败后等待的时间，之后每次翻倍
+const RETRY_BACKOFF_SECS: u64 = 30;
+const MAX_RETRY_BACKOFF_SECS: u64 = 600;
+// 同一张图最多重试的次数，超过后本次运行不再移动它
+const MAX_RETRIES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    DiskFull,
+    ReadOnly,
+    Io,
+    SourceVanished,
+    Corrupted,
+    Other,
+}
+
+impl ErrorClass {
+    pub fn classify(e: &io::Error) -> Self {
+        match e.raw_os_error() {
+            Some(libc::ENOSPC) | Some(libc::EDQUOT) => ErrorClass::DiskFull,
+            Some(libc::EROFS) => ErrorClass::ReadOnly,
+            Some(libc::EIO) | Some(libc::ENODEV) | Some(libc::ENXIO) => ErrorClass::Io,
+            _ if e.kind() == io::ErrorKind::NotFound => ErrorClass::SourceVanished,
+            _ => ErrorClass::Other,
+        }
+    }
+
+    // 这些错误说明目标盘本身有问题，暂时不再向它分配新图
+    pub fn degrades_disk(&self) -> bool {
+        matches!(
+            self,
+            ErrorClass::DiskFull | ErrorClass::ReadOnly | ErrorClass::Io | ErrorClass::Corrupted
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorClass::DiskFull => "disk full",
+            ErrorClass::ReadOnly => "read-only file system",
+            ErrorClass::Io => "I/O error",
+            ErrorClass::SourceVanished => "source vanished",
+            ErrorClass::Corrupted => "checksum mismatch",
+            ErrorClass::Other => "other error",
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub struct TransferError {
+    pub class: ErrorClass,
+    pub message: String,
+}
+
+impl TransferError {
+    pub fn new(class: ErrorClass, message: String) -> Self {
+        TransferError { class, message }
+    }
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.class)
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+impl From<io::Error> for TransferError {
+    fn from(e: io::Error) -> Self {
+        TransferError::new(ErrorClass::classify(&e), e.to_string())
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for TransferError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        match e.downcast::<io::Error>() {
+            Ok(e) => TransferError::from(*e),
+            Err(e) => TransferError::new(ErrorClass::Other, e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PlotFailure {
+    attempts: usize,
+    // None 表示不再重试
+    retry_at: Option<Instant>,
+    failed_dirs: Vec<String>,
+}
+
+// 记录传输失败的图：在退避时间内不再选择它，重试时避开已经失败过的目录
+#[derive(Debug, Default)]
+pub struct RetryBook {
+    plots: HashMap<String, PlotFailure>,
+}
+
+impl RetryBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 记录一次失败，返回下一次重试前需要等待的时间；不再重试时返回None
+    pub fn record_failure(
+        &mut self,
+        source: &str,
+        final_dir: &str,
+        retryable: bool,
+    ) -> Option<Duration> {
+        let failure = self
+            .plots
+            .entry(source.to_owned())
+            .or_insert_with(|| PlotFailure {
+                attempts: 0,
+                retry_at: None,
+                failed_dirs: vec![],
+            });
+        failure.attempts += 1;
+        if !failure.failed_dirs.iter().any(|dir| dir == final_dir) {
+            failure.failed_dirs.push(final_dir.to_owned());
+        }
+        if !retryable || failure.attempts > MAX_RETRIES {
+            failure.retry_at = None;
+            return None;
+        }
+        let backoff = (RETRY_BACKOFF_SECS << (failure.attempts - 1)).min(MAX_RETRY_BACKOFF_SECS);
+        let backoff = Duration::from_secs(backoff);
+        failure.retry_at = Some(Instant::now() + backoff);
+        Some(backoff)
+    }
+
+    // 这张图是否还在退避中或已经放弃
+    pub fn is_waiting(&self, source: &str) -> bool {
+        match self.plots.get(source) {
+            Some(failure) => match failure.retry_at {
+                Some(retry_at) => Instant::now() < retry_at,
+                None => true,
+            },
+            None => false,
+        }
+    }
+
+    // 是否有图在等待退避结束，调度循环需要定时醒来
+    pub fn has_pending_retry(&self) -> bool {
+        self.plots
+            .values()
+            .any(|failure| failure.retry_at.is_some())
+    }
+
+    pub fn failed_dirs(&self, source: &str) -> Vec<String> {
+        match self.plots.get(source) {
+            Some(failure) => failure.failed_dirs.clone(),
+            None => vec![],
+        }
+    }
+
+    pub fn forget(&mut self, source: &str) {
+        self.plots.remove(source);
+    }
+}
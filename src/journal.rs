@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferPhase {
+    // 正在复制到临时文件，offset 之前的数据已落盘
+    Copying,
+    // 临时文件已完整写入，等待重命名
+    Copied,
+    // 已重命名为正式文件，等待删除源文件
+    Renamed,
+    // 传输完成
+    Done,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct JournalEntry {
+    pub plot: String,
+    pub source: String,
+    pub final_dir: String,
+    pub temp: String,
+    pub target: String,
+    pub size: f32,
+    pub offset: u64,
+    pub phase: TransferPhase,
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    path: String,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    // 打开日志文件，并只保留未完成的记录
+    pub fn open(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let pending = read_pending(path)?;
+        {
+            let mut file = File::create(path)?;
+            for entry in pending.iter() {
+                writeln!(file, "{}", serde_json::to_string(entry)?)?;
+            }
+            file.sync_all()?;
+        }
+        let file = OpenOptions::new().append(true).open(path)?;
+        debug!(
+            "[Journal]:{} opened with {} pending entries",
+            path,
+            pending.len()
+        );
+        Ok(Journal {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, entry: &JournalEntry) -> Result<(), Box<dyn std::error::Error>> {
+        let line = serde_json::to_string(entry)?;
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    pub fn pending(&self) -> Result<Vec<JournalEntry>, Box<dyn std::error::Error>> {
+        let _file = self.file.lock().unwrap();
+        read_pending(&self.path)
+    }
+}
+
+// 日志文件与用户设置文件放在同一目录
+pub fn journal_path(config_path: &str) -> String {
+    let dir = Path::new(config_path)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    dir.join("transfer_journal.jsonl")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// 同一个临时文件以最后一条记录为准，返回所有未完成的传输
+pub fn read_pending(path: &str) -> Result<Vec<JournalEntry>, Box<dyn std::error::Error>> {
+    if !Path::new(path).exists() {
+        return Ok(vec![]);
+    }
+    let reader = BufReader::new(File::open(path)?);
+    let mut order = vec![];
+    let mut latest: HashMap<String, JournalEntry> = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // 崩溃时可能留下写了一半的最后一行
+        let entry: JournalEntry = match serde_json::from_str(&line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("[Journal]:Skip the broken line {:?}:{}", line, e);
+                continue;
+            }
+        };
+        if !latest.contains_key(&entry.temp) {
+            order.push(entry.temp.clone());
+        }
+        latest.insert(entry.temp.clone(), entry);
+    }
+    Ok(order
+        .into_iter()
+        .filter_map(|temp| latest.remove(&temp))
+        .filter(|entry| entry.phase != TransferPhase::Done)
+        .collect())
+}
@@ -1,43 +1,108 @@
+pub mod cli;
+pub mod commands;
+pub mod copy_engine;
+pub mod failure;
+pub mod journal;
+pub mod limiter;
+pub mod plot_format;
+pub mod policy;
+pub mod readiness;
 pub mod show;
+pub mod space;
+pub mod transfer;
 pub mod userset;
-use std::io::{Read, Write};
-use std::path::Path;
+pub mod watcher;
 use std::sync::{Arc, Mutex};
-use tokio::task;
-
-use log::{debug, info, warn};
-
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use log::{debug, info};
+
+pub use cli::*;
+pub use commands::*;
+pub use copy_engine::*;
+pub use failure::*;
+pub use journal::*;
+pub use limiter::*;
+pub use plot_format::*;
+pub use policy::*;
+pub use readiness::*;
 pub use show::*;
-use tokio::time;
+pub use space::*;
+pub use transfer::*;
 pub use userset::*;
+pub use watcher::*;
 
-pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
+const READY_RECHECK_SECS: u64 = 10;
+
+pub async fn run(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
     // 获取用户设置
-    let user_set = get_user_set().await?;
+    let user_set = get_user_set(config_path).await?;
     info!("[Thread main]:Get user set: {:?}.", user_set);
 
     // 获取限制速度
-    let hdd_limit_rate = user_set.hdd_limit_rate;
-    debug!("[Thread main]:Draw hdd_limit_rate: {}.", hdd_limit_rate);
+    let limit_rates = user_set.limit_rates();
+    debug!("[Thread main]:Draw limit rates: {:?}.", limit_rates);
+
+    // 生成分布图及获取所有源目录
+    let sources = user_set.source_dirs();
+    debug!("[Thread main]:Draw sources: {:?}", sources);
+
+    // 重放传输日志，找出需要续传的临时文件，清理时保留它们
+    let journal = Arc::new(Journal::open(&journal_path(config_path))?);
+    let resumable = replay_journal(&journal).await?;
+    let keep_temps: Vec<String> = resumable.iter().map(|e| e.temp.clone()).collect();
+    info!("[Thread main]:Resumable transfers: {:?}", keep_temps);
+
+    // 监听源目录，新图写完或移入时立即唤醒调度循环
+    let mut watcher = PlotWatcher::new(&sources);
+    let wake = Arc::new(Notify::new());
+    let mut checker = ReadinessChecker::new(user_set.readiness.clone());
+    let copy_set = user_set.copy.clone();
+    println!("Waiting for the first plot file...");
+    loop {
+        let (source_plots, unready) = checker.scan_ready(&sources).await?;
+        if !source_plots.is_empty() {
+            break;
+        }
+        wait_next(&mut watcher, &wake, unready).await;
+    }
 
-    // 生成分布图及获取 source_dir_path
-    let source_dir_path = user_set.source_dir_path.clone();
-    debug!("[Thread main]:Draw source_dir_path: {}", source_dir_path);
-    let s = ShowInfos::new(user_set).await?;
+    let s = ShowInfos::new(user_set, false, &keep_temps).await?;
     s.show();
 
     // 筹备Mutex:给用户展示的信息，正在进行转移的线程标记，线程数组：handles
-    let show_infos = Arc::new(Mutex::new(s));
-    let transfering_plots: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
-    let transfering_dirs: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
+    let ctx = TransferContext {
+        show_infos: Arc::new(tokio::sync::Mutex::new(s)),
+        transfering_plots: Arc::new(Mutex::new(vec![])),
+        transfering_dirs: Arc::new(Mutex::new(vec![])),
+        journal,
+        limiter: Arc::new(RateLimiter::new(limit_rates, Arc::new(SystemClock::new()))),
+        copy_set: Arc::new(copy_set),
+        retry_book: Arc::new(Mutex::new(RetryBook::new())),
+        wake: Arc::clone(&wake),
+    };
+    let show_infos = Arc::clone(&ctx.show_infos);
+    let transfering_plots = Arc::clone(&ctx.transfering_plots);
+    let transfering_dirs = Arc::clone(&ctx.transfering_dirs);
+    let retry_book = Arc::clone(&ctx.retry_book);
     let mut handles = vec![];
     debug!("[Thread main]:show_infos,transfering_plots,transfering_dirs Arc and Mutex created successfully.");
 
+    // 先继续上次中断的传输
+    for entry in resumable {
+        info!(
+            "[Thread main]:👉Resume the transfer of {} to {}",
+            entry.source, entry.final_dir
+        );
+        handles.push(start_transfer(&ctx, entry).await);
+    }
+
     // 循环判断，直到所有的finished_num == max_num
     'wait_plots: loop {
         // 判定整体剩余，若为0则推出
         let total_remaining = {
-            let show_infos_lock = show_infos.lock().unwrap();
+            let show_infos_lock = show_infos.lock().await;
             let result = show_infos_lock.total_remaining().await;
             info!(
                 "[Thread main]:🧩The remaining number of plots is {}",
@@ -50,23 +115,35 @@ pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
             info!("[Thread main]:⭐The remaining number of plots is 0,plot task finished");
             break 'wait_plots;
         }
-        // 等待源目录出现plot文件
-        let plot_names = wait_polt(&source_dir_path).await?;
+        // 等待源目录出现已经写完的plot文件;有图在等待重试时同样需要定时醒来
+        let (source_plots, unready) = checker.scan_ready(&sources).await?;
+        let unready = unready || retry_book.lock().unwrap().has_pending_retry();
+        if source_plots.is_empty() {
+            wait_next(&mut watcher, &wake, unready).await;
+            continue 'wait_plots;
+        }
         info!(
-            "[Thread main]:👀Scan the source path {},get: {:?}",
-            source_dir_path, plot_names
+            "[Thread main]:👀Scan the source paths,get: {:?}",
+            source_plots
+                .iter()
+                .map(|plot| &plot.path)
+                .collect::<Vec<_>>()
         );
 
-        // 选择一个plot文件:它不应该正在传输中
-        let choose_plot: Option<String> = {
+        // 选择一个plot文件:它不应该正在传输中或等待重试，优先级高的源目录优先
+        let choose_plot: Option<SourcePlot> = {
             let mut result = None;
             let transfering_plots_lock = transfering_plots.lock().unwrap();
-            for plot in plot_names.iter() {
-                if !transfering_plots_lock.contains(plot) {
+            let retry_book_lock = retry_book.lock().unwrap();
+            for plot in source_plots.iter() {
+                if !transfering_plots_lock.contains(&plot.path)
+                    && !retry_book_lock.is_waiting(&plot.path)
+                {
                     result = Some(plot.clone());
                     break;
                 }
             }
+            drop(retry_book_lock);
             drop(transfering_plots_lock);
             result
         };
@@ -75,15 +152,15 @@ pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
             match choose_plot {
                 Some(s) => s,
                 None => {
-                    time::sleep(time::Duration::from_secs(10)).await;
                     info!("[Thread main]:🔍Waiting to find a a non tranfering plot...");
+                    wait_next(&mut watcher, &wake, unready).await;
                     continue 'wait_plots;
                 }
             }
         };
 
         // 计算被选择的新plot 文件的大小
-        let choose_plot_path = format!("{}/{}", source_dir_path, choose_plot);
+        let choose_plot_path = choose_plot.path.clone();
         info!(
             "[Thread main]:👉The choose plot path is {}",
             choose_plot_path
@@ -93,24 +170,46 @@ pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
             "[Thread main]:👉Calculate the size of the selected plot file as {}GB",
             choose_plot_size
         );
+        let choose_plot_header = read_plot_header(&choose_plot_path).await?;
+        info!(
+            "[Thread main]:👉Read the header of the selected plot:k{}, og:{}, compression level {}",
+            choose_plot_header.k,
+            choose_plot_header.is_og(),
+            choose_plot_header.compression_level
+        );
 
         // 选择一个目录：它不应该正在传输中；然后优先选择有剩余空间的目录，若所有目录都满了，则选择remaining_num最大的；
+        // 重试的图优先换一个目录，没有其它目录可选时再回到原来的目录
         let choose_final_path = {
-            let transfering_dirs_lock = transfering_dirs.lock().unwrap();
-            let mut show_infos_lock = show_infos.lock().unwrap();
-            let result = show_infos_lock
-                .get_most_suitable_dir(&transfering_dirs_lock, choose_plot_size)
-                .await?;
-            drop(transfering_dirs_lock);
+            let transfering_dirs_snapshot = transfering_dirs.lock().unwrap().clone();
+            let failed_dirs = retry_book.lock().unwrap().failed_dirs(&choose_plot_path);
+            let mut show_infos_lock = show_infos.lock().await;
+            let mut result = None;
+            if !failed_dirs.is_empty() {
+                let mut excluded = transfering_dirs_snapshot.clone();
+                excluded.extend(failed_dirs);
+                result = show_infos_lock
+                    .get_most_suitable_dir(&excluded, &choose_plot_header, choose_plot_size)
+                    .await?;
+            }
+            if result.is_none() {
+                result = show_infos_lock
+                    .get_most_suitable_dir(
+                        &transfering_dirs_snapshot,
+                        &choose_plot_header,
+                        choose_plot_size,
+                    )
+                    .await?;
+            }
             drop(show_infos_lock);
             result
         };
 
-        // 如果能选出就开启线程，如果不能选出，就等待10秒
+        // 如果能选出就开启线程，如果不能选出，就等待新图或传输结束
         match choose_final_path {
             None => {
                 info!("[Thread main]:🔍Waiting for the most suitable directory");
-                time::sleep(time::Duration::from_secs(10)).await;
+                wait_next(&mut watcher, &wake, unready).await;
                 continue 'wait_plots;
             }
             Some(final_path) => {
@@ -123,159 +222,14 @@ pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
                     final_path
                 );
                 // 开启一个线程
-                let transfering_plots = Arc::clone(&transfering_plots);
-                let transfering_dirs = Arc::clone(&transfering_dirs);
-                let show_infos = Arc::clone(&show_infos);
-                debug!("[Thread main]:Copying Arc of transfering_plots,transfering_dirs and show_infos is accomplish");
-                let handle = task::spawn(async move {
-                    // 更新show_infs和transfering_plots
-                    {
-                        let mut transfering_plots_lock = transfering_plots.lock().unwrap();
-                        let mut transfering_dirs_lock = transfering_dirs.lock().unwrap();
-                        let mut show_infos_lock = show_infos.lock().unwrap();
-                        transfering_plots_lock.push(choose_plot.clone());
-                        transfering_dirs_lock.push(final_path.clone());
-                        show_infos_lock.change_state(&final_path);
-                        show_infos_lock.show();
-
-                        debug!(
-                            "[Thread {}]:Change state to transfering and transfering_dirs updated",
-                            final_path
-                        );
-                        debug!(
-                            "[Thread {}]:Update the transfering_plots var to {:?}",
-                            final_path, transfering_plots_lock
-                        );
-                        debug!(
-                            "[Thread {}]:Update the transfering_dirs var to {:?}",
-                            final_path, transfering_dirs_lock
-                        );
-                        drop(transfering_plots_lock);
-                        drop(show_infos_lock);
-                        drop(transfering_dirs_lock);
-                    }
-
-                    // 移动文件 choose_plot_path final_path choose_plot
-                    let source_path = Path::new(&choose_plot_path);
-                    let temp_name = format!("{}.tmp", choose_plot);
-                    let target_path = Path::new(&final_path).join(&temp_name);
-                    debug!(
-                        "[Thread {}]:Get the sourse path:{:?}",
-                        final_path, source_path
-                    );
-                    debug!(
-                        "[Thread {}]:Get the target path:{:?}",
-                        final_path, target_path
-                    );
-
-                    let mut source_file = std::fs::File::open(source_path).unwrap();
-                    let mut target_file = std::fs::File::create(target_path).unwrap();
-
-                    let mut buffer = [0; 1024 * 100];
-                    let mut total_bytes = 0;
-
-                    let start_time = time::Instant::now();
-
-                    let mut read_time = 0;
-                    loop {
-                        let bytes_read = source_file.read(&mut buffer).unwrap();
-
-                        if bytes_read == 0 {
-                            break;
-                        }
-
-                        target_file.write_all(&buffer[..bytes_read]).unwrap();
-                        // time::sleep(wait_time).await;
-
-                        total_bytes += bytes_read;
-                        let total_bytes_gb = total_bytes as f32 / 1024.0 / 1024.0 / 1024.0;
-
-                        let elapsed_time = start_time.elapsed().as_secs_f32();
-                        let transfer_rate = total_bytes as f32 / elapsed_time / 1024.0 / 1024.0;
-
-                        if read_time % 20000 == 0 {
-                            {
-                                let mut show_infos_lock = show_infos.lock().unwrap();
-                                show_infos_lock.updtate_transfering_msg(
-                                    &final_path,
-                                    transfer_rate,
-                                    total_bytes_gb,
-                                );
-                                show_infos_lock.show();
-                                drop(show_infos_lock);
-                            }
-                        }
-
-                        if transfer_rate > hdd_limit_rate {
-                            let sleep_time = time::Duration::from_millis(
-                                (bytes_read as f32 / 100.0 / 1024.0 * 1000.0) as u64,
-                            );
-                            time::sleep(sleep_time).await;
-                        }
-                        read_time += 1;
-                    }
-
-                    // 删除源文件
-                    std::fs::remove_file(&choose_plot_path).unwrap();
-                    info!(
-                        "[Thread {}]:{}:🗑Successfully deleted",
-                        final_path, choose_plot_path
-                    );
-                    // 修改文件名
-                    let temp_path = format!("{}/{}", final_path, temp_name);
-                    let target_path = format!("{}/{}", final_path, choose_plot);
-
-                    // loop 判断文件大小，大小等于源文件大小后再重命名
-                    loop {
-                        let temp_size = get_plot_size(&temp_path).await.unwrap();
-                        if temp_size == choose_plot_size {
-                            info!(
-                                "[Thread {}]:📌let {} rename to {}",
-                                final_path, temp_path, target_path
-                            );
-                            std::fs::rename(temp_path, target_path).unwrap();
-                            break;
-                        } else {
-                            info!(
-                                "[Thread {}]:Size of the{} is {}Gb, not {}Gb,so wait for 10 sec",
-                                final_path, temp_path, temp_size, choose_plot_size
-                            );
-                            time::sleep(time::Duration::from_secs(10)).await;
-                        }
-                    }
-
-                    // 更新多线程三项数据
-                    {
-                        let mut transfering_plots_lock = transfering_plots.lock().unwrap();
-                        let mut transfering_dirs_lock = transfering_dirs.lock().unwrap();
-                        let mut show_infos_lock = show_infos.lock().unwrap();
-
-                        // 更新正在传输的plot文件
-                        transfering_plots_lock.retain(|x| x != &choose_plot);
-
-                        // 更新正在传输的最终目录
-                        transfering_dirs_lock.retain(|x| x != &final_path);
-
-                        // 更新show_info
-                        show_infos_lock.change_state(&final_path);
-                        show_infos_lock.add_one_plot(&final_path, choose_plot_size);
-                        show_infos_lock.show();
-                        debug!("[Thread {}]:transfering_plots_lock,transfering_dirs_lock,show_infos_lock updated ,the thread out.", final_path);
-                        debug!(
-                            "[Thread {}]:Update transfering_plots : {:?} ",
-                            final_path, transfering_plots_lock
-                        );
-                        debug!(
-                            "[Thread {}]:Update transfering_dirs:{:?}",
-                            final_path, transfering_dirs_lock
-                        );
-                        drop(transfering_plots_lock);
-                        drop(transfering_dirs_lock);
-                        drop(show_infos_lock);
-                    }
-                });
+                let entry = JournalEntry::new(
+                    &choose_plot.name,
+                    &choose_plot_path,
+                    &final_path,
+                    choose_plot_size,
+                );
+                let handle = start_transfer(&ctx, entry).await;
                 handles.push(handle);
-                time::sleep(time::Duration::from_secs(10)).await;
             }
         }
     }
@@ -286,3 +240,60 @@ pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+// 还有未写完的图时，定时重新检查；否则等待文件事件
+async fn wait_next(watcher: &mut PlotWatcher, wake: &Notify, unready: bool) {
+    if unready {
+        watcher
+            .wait_timeout(wake, Duration::from_secs(READY_RECHECK_SECS))
+            .await;
+    } else {
+        watcher.wait(wake).await;
+    }
+}
+
+pub async fn plan(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    // 获取用户设置
+    let user_set = get_user_set(config_path).await?;
+    info!("[Dry run]:Get user set: {:?}.", user_set);
+
+    // 生成分布图，不删除任何文件
+    let sources = user_set.source_dirs();
+    let readiness = user_set.readiness.clone();
+    let mut show_infos = ShowInfos::new(user_set, true, &[]).await?;
+
+    // 依次为源目录中每一张已写完的图选择目录，模拟传输完成
+    let mut checker = ReadinessChecker::new(ReadinessSet {
+        stable_checks: 1,
+        ..readiness
+    });
+    let (source_plots, unready) = checker.scan_ready(&sources).await?;
+    if unready {
+        info!("[Dry run]:Some plots are still being written and are skipped");
+    }
+    for plot in source_plots {
+        if show_infos.total_remaining().await == 0 {
+            info!("[Dry run]:⭐The remaining number of plots is 0,stop planning");
+            break;
+        }
+        let plot_path = plot.path.clone();
+        let plot_size = get_plot_size(&plot_path).await?;
+        let plot_header = read_plot_header(&plot_path).await?;
+        match show_infos
+            .get_most_suitable_dir(&[], &plot_header, plot_size)
+            .await?
+        {
+            Some(final_path) => {
+                info!("[Dry run]:👉{} would be moved to {}", plot_path, final_path);
+                show_infos.plan_one_plot(&final_path, &plot_path, plot_size);
+            }
+            None => {
+                info!("[Dry run]:🔍No suitable directory for {}", plot_path);
+            }
+        }
+    }
+
+    show_infos.show();
+    show_infos.show_dry_run_report();
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const BYTES_PER_MB: f64 = 1024.0 * 1024.0;
+// 桶容量：允许突发1秒的流量
+const BURST_SECS: f64 = 1.0;
+
+pub trait Clock: Send + Sync {
+    // 自某个固定起点以来经过的时间
+    fn now(&self) -> Duration;
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    // 字节/秒
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Duration,
+}
+
+impl TokenBucket {
+    pub fn new(rate_mb: f32, now: Duration) -> Self {
+        let rate = rate_mb as f64 * BYTES_PER_MB;
+        TokenBucket {
+            rate,
+            capacity: rate * BURST_SECS,
+            tokens: rate * BURST_SECS,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Duration) {
+        let elapsed = now.saturating_sub(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    // 取走bytes个令牌，令牌不足时允许欠账，返回需要等待多久才能还清
+    pub fn take(&mut self, bytes: u64, now: Duration) -> Duration {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 || self.rate <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LimitRates {
+    // 每块目标盘的限速 MB/s
+    pub hdd_limit_rate: f32,
+    // 每块源盘的限速 MB/s
+    pub source_limit_rate: Option<f32>,
+    // 全局限速 MB/s
+    pub global_limit_rate: Option<f32>,
+}
+
+pub struct RateLimiter {
+    clock: Arc<dyn Clock>,
+    state: Mutex<LimiterState>,
+}
+
+struct LimiterState {
+    rates: LimitRates,
+    destinations: HashMap<String, TokenBucket>,
+    sources: HashMap<u64, TokenBucket>,
+    global: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rates: LimitRates, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
+        RateLimiter {
+            clock,
+            state: Mutex::new(LimiterState {
+                rates,
+                destinations: HashMap::new(),
+                sources: HashMap::new(),
+                global: rates
+                    .global_limit_rate
+                    .map(|rate| TokenBucket::new(rate, now)),
+            }),
+        }
+    }
+
+    // 为一次写入申请额度：同时扣除目标盘、源盘和全局三个桶，返回需要等待的最长时间
+    pub fn acquire(&self, final_dir: &str, source_dev: u64, bytes: u64) -> Duration {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        let rates = state.rates;
+
+        let mut wait = state
+            .destinations
+            .entry(final_dir.to_owned())
+            .or_insert_with(|| TokenBucket::new(rates.hdd_limit_rate, now))
+            .take(bytes, now);
+
+        if let Some(rate) = rates.source_limit_rate {
+            let source_wait = state
+                .sources
+                .entry(source_dev)
+                .or_insert_with(|| TokenBucket::new(rate, now))
+                .take(bytes, now);
+            wait = wait.max(source_wait);
+        }
+
+        if let Some(global) = state.global.as_mut() {
+            wait = wait.max(global.take(bytes, now));
+        }
+        wait
+    }
+}
@@ -1,8 +1,10 @@
+use clap::Parser;
 use hello_plot_max::*;
 use log::error;
 use std::panic;
+use std::path::Path;
 
-fn handle_panic(info: &panic::PanicInfo<'_>) {
+fn handle_panic(info: &panic::PanicHookInfo<'_>) {
     if let Some(location) = info.location() {
         error!(
             "🐞Panic occurred in file '{}' at line {}",
@@ -22,11 +24,26 @@ fn handle_panic(info: &panic::PanicInfo<'_>) {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    log4rs::init_file("./log4rs.yaml", Default::default()).unwrap();
-    std::fs::write("./log/requests.log", "")?;
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Run);
+
+    // log4rs.yaml 中的 $ENV{HPM_LOG_DIR} 指向日志目录
+    std::fs::create_dir_all(&cli.log_dir)?;
+    std::env::set_var("HPM_LOG_DIR", &cli.log_dir);
+    log4rs::init_file(&cli.log_config, Default::default()).unwrap();
+    if command == Command::Run {
+        std::fs::write(Path::new(&cli.log_dir).join("requests.log"), "")?;
+    }
     panic::set_hook(Box::new(|info| {
         handle_panic(info);
     }));
-    run().await.unwrap();
+    match command {
+        Command::Run => run(&cli.config).await.unwrap(),
+        Command::Status => status(&cli.config).await?,
+        Command::Plan => plan(&cli.config).await?,
+        Command::ValidateConfig => validate_config(&cli.config).await?,
+        Command::Scan => scan(&cli.config).await?,
+        Command::CleanTmp => clean_tmp(&cli.config).await?,
+    }
     Ok(())
 }
@@ -0,0 +1,185 @@
+use std::fs::File;
+use std::io::Read;
+
+const V1_MAGIC: &[u8] = b"Proof of Space Plot";
+const V2_MAGIC: &[u8] = b"PLOT";
+const V2_VERSION: u32 = 2;
+const V2_FLAG_COMPRESSED: u32 = 1;
+
+const PLOT_ID_LEN: usize = 32;
+const POOL_PUBLIC_KEY_LEN: usize = 48;
+const POOL_CONTRACT_LEN: usize = 32;
+const FARMER_PUBLIC_KEY_LEN: usize = 48;
+const LOCAL_MASTER_SK_LEN: usize = 32;
+const TABLE_POINTERS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlotFormat {
+    V1,
+    V2,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PoolKind {
+    // OG plot:memo 中记录的是 pool public key
+    PublicKey(Vec<u8>),
+    // NFT plot:memo 中记录的是 pool contract puzzle hash
+    Contract(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct PlotHeader {
+    pub format: PlotFormat,
+    pub format_description: String,
+    pub plot_id: [u8; PLOT_ID_LEN],
+    pub k: u8,
+    pub pool: PoolKind,
+    pub farmer_public_key: Vec<u8>,
+    pub compression_level: u8,
+    pub table_pointers: Vec<u64>,
+}
+
+impl PlotHeader {
+    pub fn is_og(&self) -> bool {
+        matches!(self.pool, PoolKind::PublicKey(_))
+    }
+
+    pub fn plot_id_hex(&self) -> String {
+        to_hex(&self.plot_id)
+    }
+
+    pub fn pool_contract_hex(&self) -> Option<String> {
+        match &self.pool {
+            PoolKind::Contract(hash) => Some(to_hex(hash)),
+            PoolKind::PublicKey(_) => None,
+        }
+    }
+
+    // 绘图程序在最后才写入表指针，全部非零且都在文件范围内才视为写完
+    pub fn is_complete(&self, file_len: u64) -> bool {
+        !self.table_pointers.is_empty()
+            && self
+                .table_pointers
+                .iter()
+                .all(|pointer| *pointer > 0 && *pointer < file_len)
+    }
+
+    // 判断两张图是否属于同一代：k值、池类型（含合约地址）、压缩等级都相同
+    pub fn same_generation(&self, other: &PlotHeader) -> bool {
+        self.k == other.k
+            && self.pool == other.pool
+            && self.compression_level == other.compression_level
+    }
+}
+
+pub async fn read_plot_header(path: &str) -> Result<PlotHeader, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    parse_plot_header(&mut file).map_err(|e| format!("{}:invalid plot header:{}", path, e).into())
+}
+
+pub fn parse_plot_header(reader: &mut impl Read) -> Result<PlotHeader, Box<dyn std::error::Error>> {
+    // 先读取4字节判断是v1还是v2格式
+    let mut head = [0_u8; 4];
+    reader.read_exact(&mut head)?;
+
+    if head == V2_MAGIC {
+        let version = u32::from_le_bytes(read_array(reader)?);
+        if version != V2_VERSION {
+            return Err(format!("unsupported plot version {}", version).into());
+        }
+        let plot_id = read_array(reader)?;
+        let k = read_array::<1>(reader)?[0];
+        let (pool, farmer_public_key) = read_memo(reader)?;
+        let flags = u32::from_le_bytes(read_array(reader)?);
+        let compression_level = if flags & V2_FLAG_COMPRESSED != 0 {
+            read_array::<1>(reader)?[0]
+        } else {
+            0
+        };
+        let table_pointers = read_table_pointers(reader)?;
+        return Ok(PlotHeader {
+            format: PlotFormat::V2,
+            format_description: format!("v{}", version),
+            plot_id,
+            k,
+            pool,
+            farmer_public_key,
+            compression_level,
+            table_pointers,
+        });
+    }
+
+    let mut rest = vec![0_u8; V1_MAGIC.len() - head.len()];
+    reader.read_exact(&mut rest)?;
+    if head[..] != V1_MAGIC[..head.len()] || rest[..] != V1_MAGIC[head.len()..] {
+        return Err("unknown plot magic".into());
+    }
+    let plot_id = read_array(reader)?;
+    let k = read_array::<1>(reader)?[0];
+    let format_description = {
+        let len = u16::from_be_bytes(read_array(reader)?) as usize;
+        let mut buf = vec![0_u8; len];
+        reader.read_exact(&mut buf)?;
+        String::from_utf8_lossy(&buf).into_owned()
+    };
+    let (pool, farmer_public_key) = read_memo(reader)?;
+    let table_pointers = read_table_pointers(reader)?;
+    Ok(PlotHeader {
+        format: PlotFormat::V1,
+        format_description,
+        plot_id,
+        k,
+        pool,
+        farmer_public_key,
+        compression_level: 0,
+        table_pointers,
+    })
+}
+
+fn read_memo(reader: &mut impl Read) -> Result<(PoolKind, Vec<u8>), Box<dyn std::error::Error>> {
+    let len = u16::from_be_bytes(read_array(reader)?) as usize;
+    let mut memo = vec![0_u8; len];
+    reader.read_exact(&mut memo)?;
+
+    // memo 布局:pool(48字节公钥 或 32字节合约) + farmer public key(48) + local master sk(32)
+    let pool_len = match len {
+        l if l == POOL_PUBLIC_KEY_LEN + FARMER_PUBLIC_KEY_LEN + LOCAL_MASTER_SK_LEN => {
+            POOL_PUBLIC_KEY_LEN
+        }
+        l if l == POOL_CONTRACT_LEN + FARMER_PUBLIC_KEY_LEN + LOCAL_MASTER_SK_LEN => {
+            POOL_CONTRACT_LEN
+        }
+        _ => return Err(format!("unexpected memo length {}", len).into()),
+    };
+    let pool_bytes = memo[..pool_len].to_vec();
+    let pool = if pool_len == POOL_PUBLIC_KEY_LEN {
+        PoolKind::PublicKey(pool_bytes)
+    } else {
+        PoolKind::Contract(pool_bytes)
+    };
+    let farmer_public_key = memo[pool_len..pool_len + FARMER_PUBLIC_KEY_LEN].to_vec();
+    Ok((pool, farmer_public_key))
+}
+
+// 文件头还没写完时表指针可能缺失，按未写完处理而不是报错
+fn read_table_pointers(reader: &mut impl Read) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+    let mut pointers = vec![];
+    for _ in 0..TABLE_POINTERS {
+        match read_array::<8>(reader) {
+            Ok(bytes) => pointers.push(u64::from_be_bytes(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(pointers)
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> std::io::Result<[u8; N]> {
+    let mut buf = [0_u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+
+use super::plot_format::*;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ReplacementRule {
+    #[serde(default)]
+    pub og: Option<bool>,
+    #[serde(default)]
+    pub k: Option<u8>,
+    #[serde(default)]
+    pub compression_level: Option<u8>,
+    #[serde(default)]
+    pub pool_contract: Option<String>,
+}
+
+impl ReplacementRule {
+    // 规则中未填写的字段视为不限制
+    pub fn matches(&self, header: &PlotHeader) -> bool {
+        if let Some(og) = self.og {
+            if header.is_og() != og {
+                return false;
+            }
+        }
+        if let Some(k) = self.k {
+            if header.k != k {
+                return false;
+            }
+        }
+        if let Some(level) = self.compression_level {
+            if header.compression_level != level {
+                return false;
+            }
+        }
+        if let Some(contract) = &self.pool_contract {
+            if !same_hex(header.pool_contract_hex().as_deref(), contract) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ReplacementPolicy {
+    #[serde(default)]
+    pub rules: Vec<ReplacementRule>,
+    #[serde(default)]
+    pub protected_contracts: Vec<String>,
+}
+
+impl ReplacementPolicy {
+    // 返回旧图的删除优先级，数字越小越先删除；None 表示这张图不允许被删除
+    pub fn rank(&self, header: &PlotHeader, new_plot_header: &PlotHeader) -> Option<usize> {
+        // 与新图同一代的图永远不删除
+        if header.same_generation(new_plot_header) {
+            return None;
+        }
+
+        // 受保护的合约地址永远不删除
+        if let Some(contract) = header.pool_contract_hex() {
+            if self
+                .protected_contracts
+                .iter()
+                .any(|protected| same_hex(Some(&contract), protected))
+            {
+                return None;
+            }
+        }
+
+        // 未配置规则时，先删除OG图，再删除其余旧图
+        if self.rules.is_empty() {
+            return Some(if header.is_og() { 0 } else { 1 });
+        }
+
+        self.rules.iter().position(|rule| rule.matches(header))
+    }
+}
+
+fn same_hex(value: Option<&str>, expected: &str) -> bool {
+    let expected = expected.trim_start_matches("0x");
+    match value {
+        Some(value) => value
+            .trim_start_matches("0x")
+            .eq_ignore_ascii_case(expected),
+        None => false,
+    }
+}
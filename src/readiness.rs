@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use super::plot_format::*;
+use super::show::*;
+use super::userset::*;
+
+const O_ACCMODE: u32 = 0o3;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReadinessSet {
+    // 文件大小连续多少次扫描不变才算写完，1 表示不检查
+    #[serde(default = "default_stable_checks")]
+    pub stable_checks: usize,
+    // 检查是否还有进程以写方式打开这个文件(/proc/*/fd)
+    #[serde(default = "default_true")]
+    pub check_open_writers: bool,
+    // 设置后要求存在 <plot>.<marker_extension> 标记文件
+    #[serde(default)]
+    pub marker_extension: Option<String>,
+    // 检查文件头中的表指针是否已写入且不超过文件大小
+    #[serde(default = "default_true")]
+    pub check_header: bool,
+}
+
+impl Default for ReadinessSet {
+    fn default() -> Self {
+        ReadinessSet {
+            stable_checks: default_stable_checks(),
+            check_open_writers: true,
+            marker_extension: None,
+            check_header: true,
+        }
+    }
+}
+
+fn default_stable_checks() -> usize {
+    1
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug)]
+pub struct ReadinessChecker {
+    set: ReadinessSet,
+    // 路径 -> (上次看到的大小, 连续相同的次数)
+    observations: HashMap<String, (u64, usize)>,
+}
+
+impl ReadinessChecker {
+    pub fn new(set: ReadinessSet) -> Self {
+        ReadinessChecker {
+            set,
+            observations: HashMap::new(),
+        }
+    }
+
+    // 判断源目录中的plot是否已经写完，可以被移动
+    pub async fn is_ready(&mut self, path: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let len = std::fs::metadata(path)?.len();
+
+        // 大小稳定
+        let stable = {
+            let observation = self.observations.entry(path.to_owned()).or_insert((len, 0));
+            if observation.0 == len {
+                observation.1 += 1;
+            } else {
+                *observation = (len, 1);
+            }
+            observation.1
+        };
+        if stable < self.set.stable_checks {
+            debug!(
+                "[Readiness]:{} size {} seen {}/{} times",
+                path, len, stable, self.set.stable_checks
+            );
+            return Ok(false);
+        }
+
+        // 标记文件
+        if let Some(extension) = &self.set.marker_extension {
+            let marker = format!("{}.{}", path, extension);
+            if !Path::new(&marker).exists() {
+                debug!("[Readiness]:{} has no marker {}", path, marker);
+                return Ok(false);
+            }
+        }
+
+        // 没有写入者
+        if self.set.check_open_writers && has_open_writer(path) {
+            debug!("[Readiness]:{} is still opened for writing", path);
+            return Ok(false);
+        }
+
+        // 文件头完整
+        if self.set.check_header {
+            match read_plot_header(path).await {
+                Ok(header) if header.is_complete(len) => {}
+                Ok(_) => {
+                    debug!("[Readiness]:{} table pointers are not written yet", path);
+                    return Ok(false);
+                }
+                Err(e) => {
+                    debug!("[Readiness]:{}", e);
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // 扫描所有源目录，返回已经写完的图，以及是否还有未写完的图
+    pub async fn scan_ready(
+        &mut self,
+        sources: &[SourceDir],
+    ) -> Result<(Vec<SourcePlot>, bool), Box<dyn std::error::Error>> {
+        let source_plots = scan_sources(sources).await?;
+
+        // 清理已经不在源目录中的记录
+        self.observations
+            .retain(|path, _| source_plots.iter().any(|plot| &plot.path == path));
+
+        let mut ready = vec![];
+        let mut unready = false;
+        for plot in source_plots {
+            match self.is_ready(&plot.path).await {
+                Ok(true) => ready.push(plot),
+                Ok(false) => unready = true,
+                // 检查期间文件可能被移走或改名
+                Err(e) => {
+                    debug!("[Readiness]:{}:{}", plot.path, e);
+                    unready = true;
+                }
+            }
+        }
+        Ok((ready, unready))
+    }
+}
+
+// 遍历 /proc/*/fd，查找以写方式打开该文件的进程；无法读取 /proc 时视为没有
+fn has_open_writer(path: &str) -> bool {
+    let target = match std::fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(_) => return false,
+    };
+    let procs = match std::fs::read_dir("/proc") {
+        Ok(procs) => procs,
+        Err(_) => return false,
+    };
+    for proc_entry in procs.flatten() {
+        let pid_dir = proc_entry.path();
+        let fds = match std::fs::read_dir(pid_dir.join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds.flatten() {
+            match std::fs::read_link(fd.path()) {
+                Ok(link) if link == target => {}
+                _ => continue,
+            }
+            let fdinfo = pid_dir.join("fdinfo").join(fd.file_name());
+            if let Ok(info) = std::fs::read_to_string(fdinfo) {
+                let flags = info
+                    .lines()
+                    .find_map(|line| line.strip_prefix("flags:"))
+                    .and_then(|flags| u32::from_str_radix(flags.trim(), 8).ok());
+                if let Some(flags) = flags {
+                    if flags & O_ACCMODE != 0 {
+                        return true;
+                    }
+                }
+            }
+        }
+    }
+    false
+}
@@ -1,45 +1,126 @@
 use std::io::Write;
-
+use std::path::Path;
+use std::time::Instant;
+
+use super::copy_engine::*;
+use super::failure::*;
+use super::plot_format::*;
+use super::policy::*;
+use super::space::*;
 use super::userset::*;
-use log::{error, info};
+use log::{error, info, warn};
 use prettytable::{Cell, Row, Table};
 use tokio::time;
 
+// 出错的盘暂停分配的时间
+const DEGRADED_SECS: u64 = 600;
+
 #[derive(Debug)]
 pub struct DiskInfo {
     path: String,
     finished_num: usize,
     max_num: usize,
     remaining_size: f32,
+    total_size: f32,
+    plots_size: f32,
+    size_limit: Option<f32>,
     transfer_rate: f32,
     total_transfered: f32,
     transfer_state: bool,
+    copy_method: Option<CopyMethod>,
+    degraded: Option<(ErrorClass, Instant)>,
+    planned_plots: Vec<String>,
+    planned_deletions: Vec<(String, f32)>,
 }
 #[derive(Debug)]
-pub struct ShowInfos(Vec<DiskInfo>);
+pub struct ShowInfos {
+    disks: Vec<DiskInfo>,
+    policy: ReplacementPolicy,
+    dry_run: bool,
+}
+
+impl DiskInfo {
+    // 出错后的一段时间内不再向这个盘分配新图
+    fn is_degraded(&mut self) -> bool {
+        match self.degraded {
+            Some((_, until)) if Instant::now() < until => true,
+            Some((class, _)) => {
+                info!("{}:Recovered from {}", self.path, class);
+                self.degraded = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    // 用statvfs重新读取剩余空间，其它程序写入的文件和文件系统开销也会被计算在内
+    fn refresh_space(&mut self) -> std::io::Result<()> {
+        let space = disk_space(&self.path)?;
+        self.total_size = space.total;
+        self.remaining_size = match self.size_limit {
+            Some(limit) => space.free.min(limit - self.plots_size),
+            None => space.free,
+        };
+        Ok(())
+    }
+}
 
 impl ShowInfos {
-    pub async fn new(user_set: UserSet) -> Result<Self, Box<dyn std::error::Error>> {
-        // 持续等待第一张图的出现
-        println!("Waiting for the first plot file...");
-        let plot_names = wait_polt(&user_set.source_dir_path).await?;
-        let plot_name = plot_names[0].clone();
-        info!("Get the first plot size{}", plot_name);
+    pub async fn new(
+        user_set: UserSet,
+        dry_run: bool,
+        keep_temps: &[String],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        // 持续等待第一张图的出现;dry run模式下不等待
+        let sources = user_set.source_dirs();
+        let source_plots = if dry_run {
+            let source_plots = scan_sources(&sources).await?;
+            if source_plots.is_empty() {
+                return Err(format!("[Dry run]:No plot file found in {:?}", sources).into());
+            }
+            source_plots
+        } else {
+            println!("Waiting for the first plot file...");
+            wait_polt(&sources).await?
+        };
 
-        // 计算第一张new plot文件的大小
-        let new_plot_size = {
-            let file_path = format!("{}/{}", user_set.source_dir_path, plot_name);
-            get_plot_size(&file_path).await?
+        // 以第一张已经写完的图的文件头作为新图的判定标准
+        let (file_path, new_plot_header) = {
+            let mut result = None;
+            for plot in source_plots {
+                let len = std::fs::metadata(&plot.path)?.len();
+                if let Ok(header) = read_plot_header(&plot.path).await {
+                    if header.is_complete(len) {
+                        result = Some((plot.path, header));
+                        break;
+                    }
+                }
+            }
+            result.ok_or("No complete plot file found in the sources")?
         };
+        info!("Get the first plot size{}", file_path);
+
+        // 计算第一张new plot文件的大小
+        let new_plot_size = get_plot_size(&file_path).await?;
         info!("得到了第一张图的大小为{}", new_plot_size);
+        info!(
+            "Get the first plot header: k{}, og:{}, compression level {}",
+            new_plot_header.k,
+            new_plot_header.is_og(),
+            new_plot_header.compression_level
+        );
 
         // 扫描每个盘的plot文件，计算出plot文件总空间，计算出新图总空间，根据已有这张图的大小和盘的allow_new_plots_num，预估出剩余容量
         let show_infos: ShowInfos = {
             let mut disks = vec![];
             for item in user_set.final_dirs {
                 // 移除残留的temp文件
-                remove_tmp(&item.path).await?;
-                info!("{}:Non plot file deletion completed.", item.path);
+                if dry_run {
+                    info!("[Dry run]:{}:Skip removing non plot files.", item.path);
+                } else {
+                    remove_tmp(&item.path, keep_temps).await?;
+                    info!("{}:Non plot file deletion completed.", item.path);
+                }
 
                 // 统计所有的plots文件
                 let plots = scan_plot(&item.path).await?;
@@ -52,8 +133,13 @@ impl ShowInfos {
                         let plot_path = format!("{}/{}", item.path, plot);
                         let plot_size = get_plot_size(&plot_path).await?;
                         finish_size += plot_size;
-                        if plot_size > new_plot_size - 0.3 && plot_size < new_plot_size + 0.3 {
-                            finished_num += 1;
+                        match read_plot_header(&plot_path).await {
+                            Ok(header) => {
+                                if header.same_generation(&new_plot_header) {
+                                    finished_num += 1;
+                                }
+                            }
+                            Err(e) => warn!("{}:Can not read plot header:{}", item.path, e),
                         }
                     }
                     (finished_num, finish_size)
@@ -63,20 +149,24 @@ impl ShowInfos {
                     item.path, finished_num, finish_size
                 );
 
-                // 计算当前剩余空间
-                let remaining_size =
-                    item.size * 1000.0 * 1000.0 * 1000.0 * 1000.0 / 1024.0 / 1024.0 / 1024.0
-                        - finish_size;
+                // 从文件系统读取真实的剩余空间；设置了size时，plot总共不超过size
+                let size_limit = item.size.map(tb_to_gb);
+                let space = disk_space(&item.path)?;
+                let remaining_size = match size_limit {
+                    Some(limit) => space.free.min(limit - finish_size),
+                    None => space.free,
+                };
                 info!(
-                    "{}:Estimated idle space is {}Gb.",
-                    item.path, remaining_size
+                    "{}:Capacity is {}Gb, idle space is {}Gb.",
+                    item.path, space.total, remaining_size
                 );
 
-                // 计算最大p盘数量
+                // 计算最大p盘数量:剩余空间加上已有plot占用的空间都可以放新图
                 let max_num = {
-                    let total_space =
-                        item.size * 1000.0 * 1000.0 * 1000.0 * 1000.0 / 1024.0 / 1024.0 / 1024.0
-                            + 10.0;
+                    let total_space = match size_limit {
+                        Some(limit) => limit.min(space.free + finish_size),
+                        None => space.free + finish_size,
+                    };
                     let max_num = total_space / new_plot_size;
 
                     max_num as usize
@@ -92,12 +182,23 @@ impl ShowInfos {
                     finished_num,
                     max_num,
                     remaining_size,
+                    total_size: space.total,
+                    plots_size: finish_size,
+                    size_limit,
                     transfer_rate: 0.0,
                     total_transfered: 0.0,
                     transfer_state: false,
+                    copy_method: None,
+                    degraded: None,
+                    planned_plots: vec![],
+                    planned_deletions: vec![],
                 });
             }
-            ShowInfos(disks)
+            ShowInfos {
+                disks,
+                policy: user_set.replacement_policy,
+                dry_run,
+            }
         };
 
         Ok(show_infos)
@@ -130,23 +231,30 @@ impl ShowInfos {
             Cell::new("Current Rate"),
             Cell::new("Tatal Transfered"),
             Cell::new("Current State"),
+            Cell::new("Copy Method"),
         ]));
 
         // 内容
-        for (id, item) in self.0.iter().enumerate() {
-            let state: &str = {
+        for (id, item) in self.disks.iter().enumerate() {
+            let state: String = {
                 if item.finished_num >= item.max_num {
-                    "Finished"
+                    "Finished".to_owned()
                 } else {
                     if item.transfer_state {
-                        "Transfering..."
+                        "Transfering...".to_owned()
+                    } else if let Some((class, _)) = item.degraded {
+                        format!("Degraded ({})", class)
                     } else {
-                        "Waiting for transfer...."
+                        "Waiting for transfer....".to_owned()
                     }
                 }
             };
             let transfer_rate = format!("{}M/s", item.transfer_rate);
             let total_transferde = format!("{}GB", item.total_transfered);
+            let copy_method = match item.copy_method {
+                Some(method) => method.to_string(),
+                None => "-".to_owned(),
+            };
             table.add_row(Row::new(vec![
                 Cell::new(&id.to_string()),
                 Cell::new(&item.path),
@@ -155,7 +263,8 @@ impl ShowInfos {
                 Cell::new(&item.remaining_size.to_string()),
                 Cell::new(&transfer_rate),
                 Cell::new(&total_transferde),
-                Cell::new(state),
+                Cell::new(&state),
+                Cell::new(&copy_method),
             ]));
         }
 
@@ -164,7 +273,7 @@ impl ShowInfos {
 
     pub async fn total_remaining(&self) -> usize {
         let mut result = 0_usize;
-        for item in self.0.iter() {
+        for item in self.disks.iter() {
             let remaining_num = item.max_num - item.finished_num;
             result += remaining_num;
         }
@@ -173,16 +282,37 @@ impl ShowInfos {
 
     pub async fn get_most_suitable_dir(
         &mut self,
-        transfering_dirs: &Vec<String>,
+        transfering_dirs: &[String],
+        choose_plot_header: &PlotHeader,
         choose_plot_size: f32,
     ) -> Result<Option<String>, Box<dyn std::error::Error>> {
         // 选择一个目录：它不应该正在传输中；然后优先选择有剩余空间的目录，若所有目录无法装下一张新图，则，则选择remaining_num最大的；
 
+        // 出错的盘和正在传输的盘一样不参与选择
+        let mut transfering_dirs = transfering_dirs.to_vec();
+        for item in self.disks.iter_mut() {
+            if item.is_degraded() {
+                transfering_dirs.push(item.path.clone());
+            }
+        }
+
+        // 传输前重新读取空闲目录的剩余空间;dry run模式下保留模拟的结果
+        if !self.dry_run {
+            for item in self.disks.iter_mut() {
+                if transfering_dirs.contains(&item.path) {
+                    continue;
+                }
+                if let Err(e) = item.refresh_space() {
+                    warn!("[Thread main]:{}:Can not read free space:{}", item.path, e);
+                }
+            }
+        }
+
         // 获取最大remaining_siz和对应的目录路径
         let (max_remaining_size, final_path) = {
             let mut max_remaining_size = 0_f32;
             let mut final_path = "";
-            for item in self.0.iter() {
+            for item in self.disks.iter() {
                 // 如果这个目录不被包括在传输线程中，且这个目录的剩余空间为当前最大
                 if !transfering_dirs.contains(&item.path)
                     && item.remaining_size > max_remaining_size
@@ -199,25 +329,80 @@ impl ShowInfos {
             return Ok(Some(final_path.to_owned()));
         }
 
-        // 若执行到这一步骤，意味着所有硬盘都是饱和状态了，则需要选择一个remaining_num最大的盘.并执行删图腾出空间的动作；
-        let (max_remaining_num, id) = {
-            let mut max_remaining_num = 0_usize;
-            let mut id = 0_usize;
-            for (ii, item) in self.0.iter().enumerate() {
+        // 若执行到这一步骤，意味着所有硬盘都是饱和状态了，则按remaining_num从大到小选择盘.并按替换策略执行删图腾出空间的动作；
+        let candidates = {
+            let mut candidates = vec![];
+            for (id, item) in self.disks.iter().enumerate() {
                 let remaining_num = item.max_num - item.finished_num;
-                if !transfering_dirs.contains(&item.path) && remaining_num > max_remaining_num {
-                    max_remaining_num = remaining_num;
-                    id = ii;
+                if !transfering_dirs.contains(&item.path) && remaining_num > 0 {
+                    candidates.push((remaining_num, id));
                 }
             }
-            (max_remaining_num, id)
+            candidates.sort_by_key(|c| std::cmp::Reverse(c.0));
+            candidates
         };
 
         // 返回
-        if max_remaining_num > 0 {
+        for (_, id) in candidates {
             // 执行删除动作，腾出空间，使得remaining_size大于 choose_plot_size
-            self.del_plot(id, choose_plot_size).await?;
-            Ok(Some(self.0[id].path.clone()))
+            if self
+                .del_plot(id, choose_plot_header, choose_plot_size)
+                .await?
+            {
+                return Ok(Some(self.disks[id].path.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    // 按替换策略挑选需要删除的旧图，若无法腾出足够空间则返回None
+    pub async fn plan_del_plot(
+        &self,
+        id: usize,
+        choose_plot_header: &PlotHeader,
+        choose_plot_size: f32,
+    ) -> Result<Option<Vec<(String, f32)>>, Box<dyn std::error::Error>> {
+        let final_path = &self.disks[id].path;
+        let plots = scan_plot(final_path).await?;
+
+        // 读取每张图的文件头，按策略给出的优先级排序，无法识别的文件不删除
+        let mut ranked = vec![];
+        for plot in plots {
+            let del_path = format!("{}/{}", final_path, plot);
+            // dry run模式下已计划删除的图不再重复计算
+            if self.disks[id]
+                .planned_deletions
+                .iter()
+                .any(|(path, _)| path == &del_path)
+            {
+                continue;
+            }
+            let del_header = match read_plot_header(&del_path).await {
+                Ok(header) => header,
+                Err(e) => {
+                    warn!("[Thread main]:Skip the unrecognized plot:{}", e);
+                    continue;
+                }
+            };
+            if let Some(rank) = self.policy.rank(&del_header, choose_plot_header) {
+                let del_size = get_plot_size(&del_path).await?;
+                ranked.push((rank, del_path, del_size));
+            }
+        }
+        ranked.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+
+        // 累加直到remaining_size>choose_plot_size
+        let mut remaining_size = self.disks[id].remaining_size;
+        let mut victims = vec![];
+        for (_, del_path, del_size) in ranked {
+            if remaining_size > choose_plot_size {
+                break;
+            }
+            remaining_size += del_size;
+            victims.push((del_path, del_size));
+        }
+        if remaining_size > choose_plot_size {
+            Ok(Some(victims))
         } else {
             Ok(None)
         }
@@ -226,41 +411,45 @@ impl ShowInfos {
     async fn del_plot(
         &mut self,
         id: usize,
+        choose_plot_header: &PlotHeader,
         choose_plot_size: f32,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let final_path = self.0[id].path.clone();
-        let plots = scan_plot(&final_path).await?;
-
-        // 循环删除，直到remaining_size>choose_plot_size
-        for plot in plots.iter() {
-            if self.0[id].remaining_size > choose_plot_size {
-                return Ok(());
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let victims = match self
+            .plan_del_plot(id, choose_plot_header, choose_plot_size)
+            .await?
+        {
+            Some(victims) => victims,
+            None => {
+                error!(
+                    "[Thread main]:{}:The replacement policy can not free enough space.",
+                    self.disks[id].path
+                );
+                return Ok(false);
             }
-            // 获取删除路径
-            let del_path = format!("{}/{}", final_path, plot);
-
-            // 获取删除大小
-            let del_size = get_plot_size(&del_path).await?;
+        };
 
-            // 判断是否为新图，若为新图则跳过
-            if del_size > choose_plot_size - 0.3 && del_size < choose_plot_size + 0.3 {
-                continue;
+        for (del_path, del_size) in victims {
+            // 执行删除;dry run模式下只记录
+            if self.dry_run {
+                info!("[Dry run]:would delete the old plot:{}", del_path);
+                self.disks[id]
+                    .planned_deletions
+                    .push((del_path.clone(), del_size));
             } else {
-                // 执行删除
                 std::fs::remove_file(&del_path)?;
                 info!("[Thread main]:delete the old plot:{}", del_path);
-
-                // 更新remaining_size
-                self.0[id].remaining_size = self.0[id].remaining_size + del_size
             }
+
+            // 更新remaining_size
+            self.disks[id].remaining_size += del_size;
+            self.disks[id].plots_size -= del_size;
         }
-        error!("[Thread main]:delete the old plot process fail.");
-        Err("删除失败..".into())
+        Ok(true)
     }
 
     pub fn change_state(&mut self, dir: &str) {
-        for item in self.0.iter_mut() {
-            if item.path == dir.to_string() {
+        for item in self.disks.iter_mut() {
+            if item.path == dir {
                 item.transfer_state = !item.transfer_state;
                 item.transfer_rate = 0.0;
                 item.total_transfered = 0.0;
@@ -275,29 +464,135 @@ impl ShowInfos {
         transfer_rate: f32,
         total_transfered: f32,
     ) {
-        for item in self.0.iter_mut() {
-            if item.path == dir.to_owned() {
+        for item in self.disks.iter_mut() {
+            if item.path == dir {
                 item.transfer_rate = transfer_rate;
                 item.total_transfered = total_transfered;
             }
         }
     }
 
+    // 记录最近一次传输使用的复制方式
+    pub fn set_copy_method(&mut self, dir: &str, method: CopyMethod) {
+        for item in self.disks.iter_mut() {
+            if item.path == dir {
+                item.copy_method = Some(method);
+            }
+        }
+    }
+
+    pub fn degrade(&mut self, dir: &str, class: ErrorClass) {
+        for item in self.disks.iter_mut() {
+            if item.path == dir {
+                warn!(
+                    "{}:Marked as degraded for {} sec because of {}",
+                    item.path, DEGRADED_SECS, class
+                );
+                item.degraded = Some((
+                    class,
+                    Instant::now() + time::Duration::from_secs(DEGRADED_SECS),
+                ));
+            }
+        }
+    }
+
     pub fn add_one_plot(&mut self, dir: &str, new_plot_size: f32) {
-        for item in self.0.iter_mut() {
-            if item.path == dir.to_owned() {
-                item.remaining_size = item.remaining_size - new_plot_size;
+        for item in self.disks.iter_mut() {
+            if item.path == dir {
+                item.remaining_size -= new_plot_size;
+                item.plots_size += new_plot_size;
                 item.finished_num += 1;
             }
         }
     }
+
+    pub fn plan_one_plot(&mut self, dir: &str, plot: &str, new_plot_size: f32) {
+        self.add_one_plot(dir, new_plot_size);
+        for item in self.disks.iter_mut() {
+            if item.path == dir {
+                item.planned_plots.push(plot.to_owned());
+            }
+        }
+    }
+
+    pub fn show_dry_run_report(&self) {
+        println!("Dry run report:nothing has been moved, created or deleted.");
+
+        let mut table = Table::new();
+        table.add_row(Row::new(vec![
+            Cell::new("ID"),
+            Cell::new("Final Path"),
+            Cell::new("Planned New Plots"),
+            Cell::new("Old Plots Deleted"),
+            Cell::new("Reclaimed Size"),
+        ]));
+        for (id, item) in self.disks.iter().enumerate() {
+            let reclaimed = item
+                .planned_deletions
+                .iter()
+                .fold(0_f32, |sum, (_, size)| sum + size);
+            table.add_row(Row::new(vec![
+                Cell::new(&id.to_string()),
+                Cell::new(&item.path),
+                Cell::new(&item.planned_plots.len().to_string()),
+                Cell::new(&item.planned_deletions.len().to_string()),
+                Cell::new(&format!("{}GB", reclaimed)),
+            ]));
+        }
+        table.print_tty(true).unwrap();
+
+        for item in self.disks.iter() {
+            for plot in item.planned_plots.iter() {
+                println!("{}:would receive {}", item.path, plot);
+            }
+            for (path, size) in item.planned_deletions.iter() {
+                println!("{}:would delete {} ({}GB)", item.path, path, size);
+            }
+        }
+    }
 }
 
-pub async fn wait_polt(source_dir_path: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+#[derive(Debug, Clone)]
+pub struct SourcePlot {
+    pub name: String,
+    pub path: String,
+    pub priority: i32,
+}
+
+// 扫描所有源目录，按优先级从高到低排列，同一优先级保持扫描顺序
+pub async fn scan_sources(
+    sources: &[SourceDir],
+) -> Result<Vec<SourcePlot>, Box<dyn std::error::Error>> {
+    let mut result = vec![];
+    for source in sources {
+        let pattern = match &source.filter {
+            Some(filter) => Some(glob::Pattern::new(filter)?),
+            None => None,
+        };
+        for name in scan_plot(&source.path).await? {
+            if let Some(pattern) = &pattern {
+                if !pattern.matches(&name) {
+                    continue;
+                }
+            }
+            result.push(SourcePlot {
+                path: format!("{}/{}", source.path, name),
+                name,
+                priority: source.priority,
+            });
+        }
+    }
+    result.sort_by_key(|plot| std::cmp::Reverse(plot.priority));
+    Ok(result)
+}
+
+pub async fn wait_polt(
+    sources: &[SourceDir],
+) -> Result<Vec<SourcePlot>, Box<dyn std::error::Error>> {
     let result = loop {
-        let plot_names = scan_plot(source_dir_path).await?;
-        if !plot_names.is_empty() {
-            break plot_names;
+        let source_plots = scan_sources(sources).await?;
+        if !source_plots.is_empty() {
+            break source_plots;
         } else {
             time::sleep(time::Duration::from_secs(10)).await;
         }
@@ -311,10 +606,17 @@ pub async fn get_plot_size(path: &str) -> Result<f32, Box<dyn std::error::Error>
     Ok(file_size)
 }
 
-pub async fn remove_tmp(final_dir: &str) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn remove_tmp(
+    final_dir: &str,
+    keep_temps: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
     let files = std::fs::read_dir(final_dir)?;
     for file in files {
         let file_path = file?.path();
+        // 日志中待续传的临时文件不删除
+        if keep_temps.iter().any(|keep| Path::new(keep) == file_path) {
+            continue;
+        }
         if let Some(extension) = file_path.extension() {
             if extension != "plot" {
                 std::fs::remove_file(file_path)?
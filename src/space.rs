@@ -0,0 +1,37 @@
+use std::ffi::CString;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+const BYTES_PER_GB: f32 = 1024.0 * 1024.0 * 1024.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DiskSpace {
+    // 文件系统总容量 GB
+    pub total: f32,
+    // 普通用户可用的剩余空间 GB，不含保留块
+    pub free: f32,
+}
+
+// 读取目录所在文件系统的真实容量和剩余空间
+pub fn disk_space(path: &str) -> io::Result<DiskSpace> {
+    let c_path = CString::new(Path::new(path).as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    let ret = unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    let block_size = stat.f_frsize as f64;
+    Ok(DiskSpace {
+        total: (stat.f_blocks as f64 * block_size / BYTES_PER_GB as f64) as f32,
+        free: (stat.f_bavail as f64 * block_size / BYTES_PER_GB as f64) as f32,
+    })
+}
+
+// 厂商标称的TB换算为GB
+pub fn tb_to_gb(size: f32) -> f32 {
+    size * 1000.0 * 1000.0 * 1000.0 * 1000.0 / BYTES_PER_GB
+}
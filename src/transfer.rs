@@ -0,0 +1,480 @@
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use log::{debug, error, info, warn};
+use tokio::sync::Notify;
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+
+use super::copy_engine::*;
+use super::failure::*;
+use super::journal::*;
+use super::limiter::*;
+use super::show::*;
+
+const VERIFY_ATTEMPTS: usize = 3;
+
+#[derive(Clone)]
+pub struct TransferContext {
+    pub show_infos: Arc<tokio::sync::Mutex<ShowInfos>>,
+    pub transfering_plots: Arc<Mutex<Vec<String>>>,
+    pub transfering_dirs: Arc<Mutex<Vec<String>>>,
+    pub journal: Arc<Journal>,
+    pub limiter: Arc<RateLimiter>,
+    pub copy_set: Arc<CopySet>,
+    pub retry_book: Arc<Mutex<RetryBook>>,
+    pub wake: Arc<Notify>,
+}
+
+impl JournalEntry {
+    pub fn new(plot: &str, source: &str, final_dir: &str, size: f32) -> Self {
+        JournalEntry {
+            plot: plot.to_owned(),
+            source: source.to_owned(),
+            final_dir: final_dir.to_owned(),
+            temp: format!("{}/{}.tmp", final_dir, plot),
+            target: format!("{}/{}", final_dir, plot),
+            size,
+            offset: 0,
+            phase: TransferPhase::Copying,
+        }
+    }
+}
+
+// 先登记正在传输的plot和目录，再开启线程，调度循环无需等待即可继续选择
+pub async fn start_transfer(ctx: &TransferContext, entry: JournalEntry) -> JoinHandle<()> {
+    let final_path = entry.final_dir.clone();
+
+    // 更新show_infs和transfering_plots
+    {
+        let mut show_infos_lock = ctx.show_infos.lock().await;
+        let mut transfering_plots_lock = ctx.transfering_plots.lock().unwrap();
+        let mut transfering_dirs_lock = ctx.transfering_dirs.lock().unwrap();
+        transfering_plots_lock.push(entry.source.clone());
+        transfering_dirs_lock.push(final_path.clone());
+        show_infos_lock.change_state(&final_path);
+        show_infos_lock.show();
+
+        debug!(
+            "[Thread {}]:Change state to transfering and transfering_dirs updated",
+            final_path
+        );
+        debug!(
+            "[Thread {}]:Update the transfering_plots var to {:?}",
+            final_path, transfering_plots_lock
+        );
+        debug!(
+            "[Thread {}]:Update the transfering_dirs var to {:?}",
+            final_path, transfering_dirs_lock
+        );
+        drop(transfering_plots_lock);
+        drop(show_infos_lock);
+        drop(transfering_dirs_lock);
+    }
+
+    task::spawn(transfer_plot(ctx.clone(), entry))
+}
+
+async fn transfer_plot(ctx: TransferContext, mut entry: JournalEntry) {
+    match run_transfer(&ctx, &mut entry).await {
+        Ok(()) => {
+            ctx.retry_book.lock().unwrap().forget(&entry.source);
+            release(&ctx, &entry, true).await;
+        }
+        Err(e) => handle_failure(&ctx, &mut entry, e).await,
+    }
+}
+
+async fn run_transfer(
+    ctx: &TransferContext,
+    entry: &mut JournalEntry,
+) -> Result<(), TransferError> {
+    let final_path = entry.final_dir.clone();
+
+    // 复制到临时文件，从日志记录的offset处继续；校验通过后才进入下一阶段
+    if entry.phase == TransferPhase::Copying {
+        ctx.journal.record(entry)?;
+        let method = fast_copy_plot(ctx, entry).await;
+        {
+            let mut show_infos_lock = ctx.show_infos.lock().await;
+            show_infos_lock.set_copy_method(&final_path, method);
+            show_infos_lock.show();
+            drop(show_infos_lock);
+        }
+        info!(
+            "[Thread {}]:Transfer {} by {}",
+            final_path, entry.source, method
+        );
+
+        if method == CopyMethod::Rename {
+            // 源文件已经直接移动到最终位置
+            entry.phase = TransferPhase::Renamed;
+            ctx.journal.record(entry)?;
+        } else {
+            if method == CopyMethod::Stream {
+                let mut attempt = 1;
+                loop {
+                    let source_hash = copy_plot(ctx, entry).await?;
+                    let target_hash = verify_hash(ctx, &entry.temp).await?;
+                    if source_hash == target_hash {
+                        info!(
+                            "[Thread {}]:✅{} verified, blake3 {}",
+                            final_path, entry.temp, target_hash
+                        );
+                        break;
+                    }
+                    error!(
+                        "[Thread {}]:❌Checksum mismatch on {} (attempt {}/{}): source {}, target {}",
+                        final_path, entry.temp, attempt, VERIFY_ATTEMPTS, source_hash, target_hash
+                    );
+                    if attempt >= VERIFY_ATTEMPTS {
+                        return Err(TransferError::new(
+                            ErrorClass::Corrupted,
+                            format!("{} failed verification {} times", entry.temp, attempt),
+                        ));
+                    }
+                    attempt += 1;
+                    entry.offset = 0;
+                    ctx.journal.record(entry)?;
+                }
+            }
+            entry.phase = TransferPhase::Copied;
+            ctx.journal.record(entry)?;
+        }
+    }
+
+    // loop 判断文件大小，大小等于源文件大小后再重命名
+    if entry.phase == TransferPhase::Copied {
+        loop {
+            let temp_size = get_plot_size(&entry.temp).await?;
+            if temp_size == entry.size {
+                info!(
+                    "[Thread {}]:📌let {} rename to {}",
+                    final_path, entry.temp, entry.target
+                );
+                std::fs::rename(&entry.temp, &entry.target)?;
+                break;
+            } else {
+                info!(
+                    "[Thread {}]:Size of the{} is {}Gb, not {}Gb,so wait for 10 sec",
+                    final_path, entry.temp, temp_size, entry.size
+                );
+                time::sleep(time::Duration::from_secs(10)).await;
+            }
+        }
+        entry.phase = TransferPhase::Renamed;
+        ctx.journal.record(entry)?;
+    }
+
+    // 删除源文件
+    if entry.phase == TransferPhase::Renamed {
+        remove_source(entry)?;
+        entry.phase = TransferPhase::Done;
+        ctx.journal.record(entry)?;
+    }
+    Ok(())
+}
+
+// 传输失败:清理临时文件，标记出错的盘，按退避时间安排这张图重试
+async fn handle_failure(ctx: &TransferContext, entry: &mut JournalEntry, e: TransferError) {
+    let final_path = entry.final_dir.clone();
+    let mut class = e.class;
+    if entry.phase == TransferPhase::Copying && !Path::new(&entry.source).exists() {
+        class = ErrorClass::SourceVanished;
+    }
+    error!(
+        "[Thread {}]:❌Transfer {} failed in phase {:?} ({}):{}",
+        final_path, entry.source, entry.phase, class, e.message
+    );
+
+    // 正式文件已经就位时只差删除源文件，保留日志记录，下次启动时再删除
+    let landed = entry.phase == TransferPhase::Renamed;
+    if !landed {
+        if Path::new(&entry.temp).exists() {
+            match std::fs::remove_file(&entry.temp) {
+                Ok(()) => info!("[Thread {}]:🗑Removed {}", final_path, entry.temp),
+                Err(e) => warn!(
+                    "[Thread {}]:Can not remove {}:{}",
+                    final_path, entry.temp, e
+                ),
+            }
+        }
+        entry.phase = TransferPhase::Done;
+        if let Err(e) = ctx.journal.record(entry) {
+            warn!("[Thread {}]:Can not record the journal:{}", final_path, e);
+        }
+    }
+
+    if class.degrades_disk() {
+        let mut show_infos_lock = ctx.show_infos.lock().await;
+        show_infos_lock.degrade(&final_path, class);
+        drop(show_infos_lock);
+    }
+
+    // 源文件已经不在或者已经到位的图不需要重试
+    let retryable = !landed && class != ErrorClass::SourceVanished;
+    let backoff =
+        ctx.retry_book
+            .lock()
+            .unwrap()
+            .record_failure(&entry.source, &final_path, retryable);
+    match backoff {
+        Some(backoff) => info!(
+            "[Thread {}]:{} will be retried on another directory in {:?}",
+            final_path, entry.source, backoff
+        ),
+        None if retryable => error!(
+            "[Thread {}]:Give up transfering {}, the source is kept",
+            final_path, entry.source
+        ),
+        None => {}
+    }
+
+    release(ctx, entry, landed).await;
+}
+
+async fn release(ctx: &TransferContext, entry: &JournalEntry, finished: bool) {
+    let final_path = &entry.final_dir;
+
+    // 更新多线程三项数据
+    {
+        let mut show_infos_lock = ctx.show_infos.lock().await;
+        let mut transfering_plots_lock = ctx.transfering_plots.lock().unwrap();
+        let mut transfering_dirs_lock = ctx.transfering_dirs.lock().unwrap();
+
+        // 更新正在传输的plot文件
+        transfering_plots_lock.retain(|x| x != &entry.source);
+
+        // 更新正在传输的最终目录
+        transfering_dirs_lock.retain(|x| x != final_path);
+
+        // 更新show_info
+        show_infos_lock.change_state(final_path);
+        if finished {
+            show_infos_lock.add_one_plot(final_path, entry.size);
+        }
+        show_infos_lock.show();
+        debug!("[Thread {}]:transfering_plots_lock,transfering_dirs_lock,show_infos_lock updated ,the thread out.", final_path);
+        debug!(
+            "[Thread {}]:Update transfering_plots : {:?} ",
+            final_path, transfering_plots_lock
+        );
+        debug!(
+            "[Thread {}]:Update transfering_dirs:{:?}",
+            final_path, transfering_dirs_lock
+        );
+        drop(transfering_plots_lock);
+        drop(transfering_dirs_lock);
+        drop(show_infos_lock);
+    }
+
+    // 唤醒调度循环
+    ctx.wake.notify_one();
+}
+
+// 同一文件系统上优先用重命名或内核复制，不可用或出错时返回Stream，由调用者逐字节复制
+async fn fast_copy_plot(ctx: &TransferContext, entry: &JournalEntry) -> CopyMethod {
+    // 续传的临时文件只能继续逐字节复制
+    if !ctx.copy_set.fast_path || entry.offset > 0 {
+        return CopyMethod::Stream;
+    }
+    let final_path = entry.final_dir.clone();
+    let limiter = Arc::clone(&ctx.limiter);
+    let job_entry = entry.clone();
+    let result = joined(
+        task::spawn_blocking(move || {
+            let job = CopyJob {
+                source: &job_entry.source,
+                target: &job_entry.temp,
+                offset: 0,
+                final_dir: &job_entry.final_dir,
+            };
+            fast_copy(&job, &job_entry.target, &limiter)
+        })
+        .await,
+    );
+
+    match result {
+        Ok(Some(CopyMethod::CopyFileRange)) => {
+            // 内核复制的数据同样需要校验，reflink共享同一份数据则不需要
+            let hashes = match verify_hash(ctx, &entry.source).await {
+                Ok(source_hash) => verify_hash(ctx, &entry.temp)
+                    .await
+                    .map(|target_hash| (source_hash, target_hash)),
+                Err(e) => Err(e),
+            };
+            let (source_hash, target_hash) = match hashes {
+                Ok(hashes) => hashes,
+                Err(e) => {
+                    warn!(
+                        "[Thread {}]:Can not verify {}, fall back to copying:{}",
+                        final_path, entry.temp, e
+                    );
+                    return CopyMethod::Stream;
+                }
+            };
+            if source_hash == target_hash {
+                info!(
+                    "[Thread {}]:✅{} verified, blake3 {}",
+                    final_path, entry.temp, target_hash
+                );
+                CopyMethod::CopyFileRange
+            } else {
+                error!(
+                    "[Thread {}]:❌Checksum mismatch on {} after copy_file_range: source {}, target {}",
+                    final_path, entry.temp, source_hash, target_hash
+                );
+                CopyMethod::Stream
+            }
+        }
+        Ok(Some(method)) => method,
+        Ok(None) => CopyMethod::Stream,
+        Err(e) => {
+            warn!(
+                "[Thread {}]:Fast path for {} failed, fall back to copying:{}",
+                final_path, entry.source, e
+            );
+            CopyMethod::Stream
+        }
+    }
+}
+
+async fn copy_plot(
+    ctx: &TransferContext,
+    entry: &mut JournalEntry,
+) -> Result<blake3::Hash, TransferError> {
+    let final_path = entry.final_dir.clone();
+    debug!(
+        "[Thread {}]:Get the sourse path:{:?}",
+        final_path, entry.source
+    );
+    debug!(
+        "[Thread {}]:Get the target path:{:?}",
+        final_path, entry.temp
+    );
+    if entry.offset > 0 {
+        info!(
+            "[Thread {}]:Resume {} from offset {}",
+            final_path, entry.temp, entry.offset
+        );
+    }
+
+    // 复制是阻塞的，放到单独的线程中执行，不占用异步运行时的工作线程
+    let ctx = ctx.clone();
+    let mut progress_entry = entry.clone();
+    let outcome = joined(
+        task::spawn_blocking(move || {
+            let source = progress_entry.source.clone();
+            let temp = progress_entry.temp.clone();
+            let job = CopyJob {
+                source: &source,
+                target: &temp,
+                offset: progress_entry.offset,
+                final_dir: &final_path,
+            };
+            copy_file(&ctx.copy_set, &job, &ctx.limiter, |progress| {
+                // 落盘后记录进度，崩溃后可从这里继续
+                progress_entry.offset = progress.offset;
+                ctx.journal
+                    .record(&progress_entry)
+                    .map_err(|e| io::Error::other(e.to_string()))?;
+                let total_bytes_gb = progress.offset as f32 / 1024.0 / 1024.0 / 1024.0;
+                let mut show_infos_lock = ctx.show_infos.blocking_lock();
+                show_infos_lock.updtate_transfering_msg(&final_path, progress.rate, total_bytes_gb);
+                show_infos_lock.show();
+                drop(show_infos_lock);
+                Ok(())
+            })
+        })
+        .await,
+    )?;
+
+    entry.offset = outcome.bytes;
+    Ok(outcome.hash)
+}
+
+// 重新读取目标文件计算哈希
+async fn verify_hash(ctx: &TransferContext, path: &str) -> Result<blake3::Hash, TransferError> {
+    let copy_set = Arc::clone(&ctx.copy_set);
+    let path = path.to_owned();
+    joined(task::spawn_blocking(move || hash_file(&path, &copy_set)).await)
+}
+
+// 阻塞线程panic时同样作为传输错误处理
+fn joined<T>(result: Result<io::Result<T>, task::JoinError>) -> Result<T, TransferError> {
+    match result {
+        Ok(result) => Ok(result?),
+        Err(e) => Err(TransferError::new(ErrorClass::Other, e.to_string())),
+    }
+}
+
+fn remove_source(entry: &JournalEntry) -> io::Result<()> {
+    // 正式文件不存在时不删除源文件
+    if !Path::new(&entry.target).exists() {
+        warn!(
+            "[Thread {}]:{} is missing, keep the source {}",
+            entry.final_dir, entry.target, entry.source
+        );
+        return Ok(());
+    }
+    if Path::new(&entry.source).exists() {
+        std::fs::remove_file(&entry.source)?;
+        info!(
+            "[Thread {}]:{}:🗑Successfully deleted",
+            entry.final_dir, entry.source
+        );
+    }
+    Ok(())
+}
+
+// 启动时重放日志:完成待重命名和待删除源文件的传输，返回需要续传的记录
+pub async fn replay_journal(
+    journal: &Journal,
+) -> Result<Vec<JournalEntry>, Box<dyn std::error::Error>> {
+    let mut resumable = vec![];
+    for mut entry in journal.pending()? {
+        info!("[Journal]:Replay {:?}", entry);
+        match entry.phase {
+            TransferPhase::Copying => {
+                if !Path::new(&entry.source).exists() {
+                    warn!(
+                        "[Journal]:Source {} vanished, the partial copy {} can not be resumed",
+                        entry.source, entry.temp
+                    );
+                    if Path::new(&entry.temp).exists() {
+                        std::fs::remove_file(&entry.temp)?;
+                    }
+                    entry.phase = TransferPhase::Done;
+                    journal.record(&entry)?;
+                    continue;
+                }
+                // 以实际落盘的长度为准
+                let temp_len = match std::fs::metadata(&entry.temp) {
+                    Ok(metadata) => metadata.len(),
+                    Err(_) => 0,
+                };
+                entry.offset = entry.offset.min(temp_len);
+                resumable.push(entry);
+            }
+            TransferPhase::Copied => {
+                if Path::new(&entry.temp).exists() {
+                    info!("[Journal]:📌let {} rename to {}", entry.temp, entry.target);
+                    std::fs::rename(&entry.temp, &entry.target)?;
+                }
+                entry.phase = TransferPhase::Renamed;
+                journal.record(&entry)?;
+                remove_source(&entry)?;
+                entry.phase = TransferPhase::Done;
+                journal.record(&entry)?;
+            }
+            TransferPhase::Renamed => {
+                remove_source(&entry)?;
+                entry.phase = TransferPhase::Done;
+                journal.record(&entry)?;
+            }
+            TransferPhase::Done => {}
+        }
+    }
+    Ok(resumable)
+}
@@ -1,23 +1,75 @@
 use serde::{Deserialize, Serialize};
 
+use super::copy_engine::*;
+use super::limiter::*;
+use super::policy::*;
+use super::readiness::*;
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct UserSet {
-    pub source_dir_path: String,
+    #[serde(default)]
+    pub source_dir_path: Option<String>,
+    #[serde(default)]
+    pub sources: Vec<SourceDir>,
     pub hdd_limit_rate: f32,
+    #[serde(default)]
+    pub source_limit_rate: Option<f32>,
+    #[serde(default)]
+    pub global_limit_rate: Option<f32>,
     pub final_dirs: Vec<FinalDir>,
+    #[serde(default)]
+    pub replacement_policy: ReplacementPolicy,
+    #[serde(default)]
+    pub readiness: ReadinessSet,
+    #[serde(default)]
+    pub copy: CopySet,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SourceDir {
+    pub path: String,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub filter: Option<String>,
+}
+
+impl UserSet {
+    pub fn limit_rates(&self) -> LimitRates {
+        LimitRates {
+            hdd_limit_rate: self.hdd_limit_rate,
+            source_limit_rate: self.source_limit_rate,
+            global_limit_rate: self.global_limit_rate,
+        }
+    }
+
+    // 兼容旧的 source_dir_path 写法，与 sources 合并为一个列表
+    pub fn source_dirs(&self) -> Vec<SourceDir> {
+        let mut result = vec![];
+        if let Some(path) = &self.source_dir_path {
+            result.push(SourceDir {
+                path: path.clone(),
+                priority: 0,
+                filter: None,
+            });
+        }
+        result.extend(self.sources.iter().cloned());
+        result
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct FinalDir {
     pub path: String,
-    pub size: f32,
+    #[serde(default)]
+    pub size: Option<f32>,
 }
 
-pub async fn get_user_set() -> Result<UserSet, Box<dyn std::error::Error>> {
-    // 读取"./userset.json"
+pub async fn get_user_set(config_path: &str) -> Result<UserSet, Box<dyn std::error::Error>> {
+    // 读取用户设置文件，默认为"./userset.json"
     let use_set: UserSet = {
-        // 读取"./userset.json"
-        let set_str = std::fs::read_to_string("./userset.json")?;
+        let set_str = std::fs::read_to_string(config_path)
+            .map_err(|e| format!("Can not read {}:{}", config_path, e))?;
 
         // 序列化
         serde_json::from_str(&set_str)?
@@ -0,0 +1,126 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use log::{debug, info, warn};
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::{mpsc, Notify};
+use tokio::time;
+
+use super::show::*;
+use super::userset::*;
+
+// 没有任何事件时，最多等待这么久后重新扫描一次
+const IDLE_RESCAN_SECS: u64 = 60;
+const POLL_INTERVAL_SECS: u64 = 10;
+
+pub struct PlotWatcher {
+    rx: mpsc::UnboundedReceiver<String>,
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl PlotWatcher {
+    // 优先使用inotify监听源目录，失败时退回到定时扫描
+    pub fn new(sources: &[SourceDir]) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        match watch_sources(sources, tx.clone()) {
+            Ok(watcher) => {
+                info!(
+                    "[Watcher]:Watching {:?} with filesystem notifications",
+                    sources
+                );
+                PlotWatcher {
+                    rx,
+                    _watcher: Some(watcher),
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "[Watcher]:Filesystem notifications unavailable ({}), fall back to polling every {} sec",
+                    e, POLL_INTERVAL_SECS
+                );
+                tokio::spawn(poll_sources(sources.to_vec(), tx));
+                PlotWatcher { rx, _watcher: None }
+            }
+        }
+    }
+
+    pub async fn wait(&mut self, wake: &Notify) {
+        self.wait_timeout(wake, time::Duration::from_secs(IDLE_RESCAN_SECS))
+            .await
+    }
+
+    // 等待新图就绪、其它任务唤醒或超时，之后由调用者重新扫描
+    pub async fn wait_timeout(&mut self, wake: &Notify, timeout: time::Duration) {
+        tokio::select! {
+            plot = self.rx.recv() => {
+                if let Some(plot) = plot {
+                    info!("[Watcher]:📥Plot ready:{}", plot);
+                }
+            }
+            _ = wake.notified() => {
+                debug!("[Watcher]:Woken up by a finished transfer");
+            }
+            _ = time::sleep(timeout) => {
+                debug!("[Watcher]:No event for {:?}, rescan", timeout);
+            }
+        }
+        // 合并同一时刻到达的多个事件
+        while self.rx.try_recv().is_ok() {}
+    }
+}
+
+fn watch_sources(
+    sources: &[SourceDir],
+    tx: mpsc::UnboundedSender<String>,
+) -> notify::Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+        let event = match res {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("[Watcher]:{}", e);
+                return;
+            }
+        };
+        // 只关心写完关闭和重命名进来的plot文件
+        let ready = matches!(
+            event.kind,
+            EventKind::Access(AccessKind::Close(AccessMode::Write))
+                | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        );
+        if !ready {
+            return;
+        }
+        for path in event.paths {
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "plot")
+            {
+                let _ = tx.send(path.to_string_lossy().into_owned());
+            }
+        }
+    })?;
+    for source in sources {
+        watcher.watch(Path::new(&source.path), RecursiveMode::NonRecursive)?;
+    }
+    Ok(watcher)
+}
+
+async fn poll_sources(sources: Vec<SourceDir>, tx: mpsc::UnboundedSender<String>) {
+    let mut seen = HashSet::new();
+    loop {
+        match scan_sources(&sources).await {
+            Ok(plots) => {
+                let current: HashSet<String> = plots.into_iter().map(|plot| plot.path).collect();
+                for path in current.difference(&seen) {
+                    if tx.send(path.clone()).is_err() {
+                        return;
+                    }
+                }
+                seen = current;
+            }
+            Err(e) => warn!("[Watcher]:Scan the sources failed:{}", e),
+        }
+        time::sleep(time::Duration::from_secs(POLL_INTERVAL_SECS)).await;
+    }
+}